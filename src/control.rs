@@ -0,0 +1,338 @@
+// Local control endpoint the query subcommands talk to
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::task::spawn;
+use futures::channel::{mpsc, oneshot};
+use futures::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use futures::prelude::*;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+
+use crate::crypto::{self, DbKey};
+use crate::db::config_dir;
+use crate::netcommand::*;
+use crate::types::Config;
+
+// far above any real request, a longer line is refused instead of buffered
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+/// Socket in the user's runtime dir, so each user on a machine has their own daemon
+pub fn socket_path() -> PathBuf {
+    let dir = match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime) if !runtime.is_empty() => {
+            let dir = PathBuf::from(runtime).join("org-sync");
+            if !dir.is_dir() {
+                std::fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(&dir)
+                    .expect("Unable to make runtime path");
+            }
+            dir
+        },
+        // the config dir is owner-only too
+        _ => config_dir()
+    };
+    dir.join("control.sock")
+}
+
+/// Bind the control socket owner-only, replacing a socket left by a daemon that died
+pub async fn bind_unix() -> std::io::Result<UnixListener> {
+    let path = socket_path();
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("a daemon is already listening on {:?}", path)))
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path).await?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+// uid of the process on the other end of a unix socket
+#[cfg(target_os = "linux")]
+fn peer_uid(fd: RawFd) -> Option<u32> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if res == 0 { Some(cred.uid) } else { None }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(fd: RawFd) -> Option<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    let res = unsafe { libc::getpeereid(fd, &mut uid, &mut gid) };
+    if res == 0 { Some(uid) } else { None }
+}
+
+// a peer whose credentials can't be read is treated as someone else
+fn same_user(peer: Option<u32>, own: u32) -> bool {
+    peer == Some(own)
+}
+
+// the socket mode keeps other users out, the credential check makes sure
+async fn refuse_other_user(stream: &UnixStream) -> bool {
+    if same_user(peer_uid(stream.as_raw_fd()), unsafe { libc::geteuid() }) {
+        return false
+    }
+    println!("Refused control connection from another user");
+    let error: CliReply = Err(CliError::new(
+        CliErrorKind::PermissionDenied, "Daemon belongs to another user".into()));
+    _ = (&*stream).write_all(reply_line(&error).as_bytes()).await;
+    true
+}
+
+/// Serve nothing but unlock requests until the database key is unwrapped
+pub async fn wait_for_unlock(listener: &UnixListener) -> DbKey {
+    println!("Database is encrypted, waiting for `org-syncer query unlock`");
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Control connection failed: {:?}", e);
+                continue
+            }
+        };
+        if refuse_other_user(&stream).await {
+            continue
+        }
+        // clients are served one at a time, nothing else runs until this succeeds
+        let mut reader = BufReader::new(stream.clone());
+        while let Some(line) = next_line(&mut reader).await {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    _ = stream.write_all(reply_line(&Err(e)).as_bytes()).await;
+                    break
+                }
+            };
+            if line.trim().is_empty() {
+                continue
+            }
+            let (reply, key) = unlock_request(&line);
+            _ = stream.write_all(reply_line(&reply).as_bytes()).await;
+            if let Some(key) = key {
+                println!("Database unlocked");
+                return key
+            }
+        }
+    }
+    panic!("Control socket closed while waiting for unlock")
+}
+
+fn unlock_request(line: &str) -> (CliReply, Option<DbKey>) {
+    let error = |kind, message: &str| (Err(CliError::new(kind, message.into())), None);
+    let request = match serde_json::from_str::<ControlRequest>(line) {
+        Ok(request) => request,
+        Err(e) => return error(CliErrorKind::BadRequest, &e.to_string())
+    };
+    if request.version != CONTROL_VERSION {
+        return error(CliErrorKind::UnsupportedVersion,
+                     &format!("Daemon speaks version {}, client sent {}", CONTROL_VERSION, request.version))
+    }
+    let (secret, create) = match request.command {
+        CliCommand::Unlock { secret, create } => (secret, create),
+        _ => return error(CliErrorKind::Locked, "Database is locked, run `org-syncer query unlock` first")
+    };
+    let secret = match secret.to_bytes() {
+        Ok(secret) => secret,
+        Err(e) => return error(CliErrorKind::Io, &format!("Unable to read key file: {}", e))
+    };
+    if crypto::db_encrypted(&config_dir()) {
+        return match crypto::unlock_db_key(&config_dir(), &secret) {
+            Some(key) => (Ok(CliResponse::Done { message: "Database unlocked".into() }), Some(key)),
+            None => error(CliErrorKind::PermissionDenied, "Wrong passphrase or key file")
+        }
+    }
+    if !create {
+        return error(CliErrorKind::NotFound, "No database key yet, run `org-syncer query unlock --new` to set one")
+    }
+    match crypto::create_db_key(&config_dir(), &secret) {
+        Ok(key) => (Ok(CliResponse::Done { message: "Database key created and database unlocked".into() }), Some(key)),
+        Err(e) => error(CliErrorKind::Io, &format!("Unable to write database key: {}", e))
+    }
+}
+
+#[derive(Clone)]
+pub struct CliInterface {
+    pub sender: mpsc::Sender<CliRequest>
+}
+
+impl CliInterface {
+    // one task per client so a slow or broken client can't hold up the others
+    pub async fn run_unix(self, listener: UnixListener) {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    if refuse_other_user(&stream).await {
+                        continue
+                    }
+                    spawn(self.clone().handle_connection(stream));
+                },
+                Err(e) => println!("Control connection failed: {:?}", e)
+            }
+        }
+    }
+
+    pub async fn run_tcp(self, listener: TcpListener) {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    spawn(self.clone().handle_connection(stream));
+                },
+                Err(e) => println!("Control connection failed: {:?}", e)
+            }
+        }
+    }
+
+    // each line is a JSON ControlRequest, each gets a JSON ControlResponse line back
+    async fn handle_connection<S>(mut self, mut stream: S)
+    where S: AsyncRead + AsyncWrite + Clone + Unpin {
+        let mut reader = BufReader::new(stream.clone());
+        while let Some(line) = next_line(&mut reader).await {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    // the rest of an overlong line can't be told apart from a new request
+                    _ = stream.write_all(reply_line(&Err(e)).as_bytes()).await;
+                    return
+                }
+            };
+            if line.trim().is_empty() {
+                continue
+            }
+            let reply = match serde_json::from_str::<ControlRequest>(&line) {
+                Ok(request) if request.version != CONTROL_VERSION => Err(CliError::new(
+                    CliErrorKind::UnsupportedVersion,
+                    format!("Daemon speaks version {}, client sent {}", CONTROL_VERSION, request.version))),
+                Ok(request) => self.request(request.command).await,
+                Err(e) => Err(CliError::new(CliErrorKind::BadRequest, e.to_string()))
+            };
+            if stream.write_all(reply_line(&reply).as_bytes()).await.is_err() {
+                return
+            }
+        }
+    }
+
+    async fn request(&mut self, command: CliCommand) -> CliReply {
+        let (sender, receiver) = oneshot::channel();
+        let unavailable = || CliError::new(CliErrorKind::Unavailable, "Daemon is shutting down".into());
+        self.sender.send(CliRequest { command, sender }).await.map_err(|_| unavailable())?;
+        receiver.await.map_err(|_| unavailable())?
+    }
+}
+
+/// The next request line, None once the client is gone. Lines are read at most
+/// MAX_REQUEST_BYTES at a time so a client can't make the daemon buffer without limit
+async fn next_line<R>(reader: &mut R) -> Option<Result<String, CliError>>
+where R: AsyncBufRead + Unpin {
+    let mut line = vec![];
+    match reader.take(MAX_REQUEST_BYTES + 1).read_until(b'\n', &mut line).await {
+        Ok(0) | Err(_) => None,
+        Ok(read) if read as u64 > MAX_REQUEST_BYTES => Some(Err(CliError::new(
+            CliErrorKind::BadRequest, format!("Request longer than {} bytes", MAX_REQUEST_BYTES)))),
+        Ok(_) => Some(String::from_utf8(line).map_err(|_| CliError::new(
+            CliErrorKind::BadRequest, "Request is not valid UTF-8".into())))
+    }
+}
+
+fn reply_line(reply: &CliReply) -> String {
+    let response = ControlResponse { version: CONTROL_VERSION, result: reply.clone() };
+    let mut line = serde_json::to_string(&response).unwrap();
+    line.push('\n');
+    line
+}
+
+async fn exchange<S>(mut stream: S, command: CliCommand) -> std::io::Result<CliReply>
+where S: AsyncRead + AsyncWrite + Clone + Unpin {
+    let request = ControlRequest { version: CONTROL_VERSION, command };
+    let mut line = serde_json::to_string(&request).unwrap();
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).await?;
+    let response = serde_json::from_str::<ControlResponse>(&reply)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    if response.version != CONTROL_VERSION {
+        return Ok(Err(CliError::new(
+            CliErrorKind::UnsupportedVersion,
+            format!("Daemon speaks version {}, client expects {}", response.version, CONTROL_VERSION))))
+    }
+    Ok(response.result)
+}
+
+/// Send one command to the daemon, over TCP when the config asks for it
+pub async fn query(config: &Config, command: CliCommand) -> std::io::Result<CliReply> {
+    match tcp_address(config, &command) {
+        Some(addr) => exchange(TcpStream::connect(addr).await?, command).await,
+        None => exchange(UnixStream::connect(socket_path()).await?, command).await
+    }
+}
+
+// a locked daemon only listens on the Unix socket, the TCP one is bound after unlock
+fn tcp_address<'a>(config: &'a Config, command: &CliCommand) -> Option<&'a String> {
+    match command {
+        CliCommand::Unlock { .. } => None,
+        _ => config.control_tcp.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(control_tcp: Option<&str>) -> Config {
+        let mut config: Config = serde_json::from_str(r#"{"version": "v", "listen": "l", "paths": []}"#).unwrap();
+        config.control_tcp = control_tcp.map(String::from);
+        config
+    }
+
+    #[test]
+    fn unlock_goes_over_the_unix_socket() {
+        let tcp = config(Some("127.0.0.1:1324"));
+        let unlock = CliCommand::Unlock { secret: DbSecret::Passphrase("secret".into()), create: false };
+        assert_eq!(tcp_address(&tcp, &unlock), None);
+        assert_eq!(tcp_address(&tcp, &CliCommand::Status).map(String::as_str), Some("127.0.0.1:1324"));
+        assert_eq!(tcp_address(&config(None), &CliCommand::Status), None);
+    }
+
+    #[test]
+    fn overlong_and_invalid_lines_are_bad_requests() {
+        let read = |bytes: Vec<u8>| async_std::task::block_on(async move {
+            let mut reader = futures::io::Cursor::new(bytes);
+            let mut lines = vec![];
+            while let Some(line) = next_line(&mut reader).await {
+                lines.push(line.map_err(|e| e.kind));
+            }
+            lines
+        });
+        assert_eq!(read(b"{}\n{}".to_vec()), vec![Ok("{}\n".to_string()), Ok("{}".to_string())]);
+        assert_eq!(read(b"\xff\xfe\n".to_vec()), vec![Err(CliErrorKind::BadRequest)]);
+        let overlong = [vec![b'a'; MAX_REQUEST_BYTES as usize + 1], b"\n".to_vec()].concat();
+        assert_eq!(read(overlong)[0], Err(CliErrorKind::BadRequest));
+        let longest = [vec![b'a'; MAX_REQUEST_BYTES as usize - 1], b"\n".to_vec()].concat();
+        assert!(read(longest)[0].is_ok());
+    }
+
+    #[test]
+    fn only_the_daemons_user_is_served() {
+        let own = unsafe { libc::geteuid() };
+        assert!(same_user(Some(own), own));
+        assert!(!same_user(Some(own.wrapping_add(1)), own));
+        assert!(!same_user(None, own));
+        let (client, _server) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert!(same_user(peer_uid(client.as_raw_fd()), own));
+    }
+}
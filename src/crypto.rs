@@ -0,0 +1,449 @@
+// Group key shared by paired devices, everything sent to peers is sealed with it,
+// and the key the local database is sealed with when encryption at rest is on
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+use crate::db;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+// a key and the sealing and hashing keys derived from it for one use
+#[derive(Clone)]
+struct Keys {
+    key: [u8; KEY_LEN],
+    // separate keys so the key hash can't be used against the cipher
+    seal_key: [u8; KEY_LEN],
+    hash_key: [u8; KEY_LEN],
+}
+
+impl Keys {
+    fn new(key: [u8; KEY_LEN], seal_label: &[u8], hash_label: &[u8]) -> Self {
+        Self {
+            key,
+            seal_key: derive(&key, seal_label),
+            hash_key: derive(&key, hash_label),
+        }
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.hash_key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+fn random_key() -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+#[derive(Clone)]
+pub struct GroupKey {
+    keys: Keys,
+    // when the key was made, the newest key wins when devices disagree
+    pub epoch: i64,
+    // devices the key was rotated away from, it is never handed to them
+    pub exclude: Vec<Vec<u8>>,
+}
+
+// the group key as stored and as handed between devices
+#[derive(Serialize, Deserialize)]
+struct StoredGroup {
+    key: Vec<u8>,
+    epoch: i64,
+    exclude: Vec<Vec<u8>>,
+}
+
+fn derive(key: &[u8], label: &[u8]) -> [u8; KEY_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+/// nonce followed by ciphertext
+fn seal_with(key: &[u8; KEY_LEN], plaintext: &[u8]) -> Vec<u8> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(XNonce::from_slice(&nonce), plaintext).expect("Encryption to succeed"));
+    sealed
+}
+
+fn open_with(key: &[u8; KEY_LEN], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None
+    }
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()
+}
+
+impl GroupKey {
+    pub fn generate() -> Self {
+        Self::from_key(random_key(), chrono::Utc::now().timestamp_nanos(), vec![])
+    }
+
+    /// A fresh key for the same group, still kept from the devices this one was kept from
+    pub fn rotate(&self, exclude: Vec<Vec<u8>>) -> Self {
+        let mut exclude = exclude;
+        for peer in &self.exclude {
+            if !exclude.contains(peer) {
+                exclude.push(peer.clone());
+            }
+        }
+        Self { exclude, ..Self::generate() }
+    }
+
+    fn from_key(key: [u8; KEY_LEN], epoch: i64, exclude: Vec<Vec<u8>>) -> Self {
+        Self { keys: Keys::new(key, b"org-sync seal", b"org-sync dht key"), epoch, exclude }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        bendy::serde::to_bytes(&StoredGroup {
+            key: self.keys.key.to_vec(),
+            epoch: self.epoch,
+            exclude: self.exclude.clone(),
+        }).unwrap()
+    }
+
+    /// Earlier versions kept only the 32 key bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() == KEY_LEN {
+            return Some(Self::from_key(bytes.try_into().ok()?, 0, vec![]))
+        }
+        let stored = bendy::serde::from_bytes::<StoredGroup>(bytes).ok()?;
+        Some(Self::from_key(stored.key.try_into().ok()?, stored.epoch, stored.exclude))
+    }
+
+    /// Ties between keys made at the same time go the same way on every device
+    pub fn newer_than(&self, other: &GroupKey) -> bool {
+        (self.epoch, self.keys.key) > (other.epoch, other.keys.key)
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        seal_with(&self.keys.seal_key, plaintext)
+    }
+
+    /// None when the data was sealed with another key or tampered with
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        open_with(&self.keys.seal_key, sealed)
+    }
+
+    /// DHT record key for a file key, peers outside the group can't tell which file it is
+    pub fn hash_key(&self, key: &[u8]) -> Vec<u8> {
+        self.keys.hash(key)
+    }
+
+    /// Replace the saved key, a device joining a group takes on the group's key
+    pub fn save(&self, db: &mut db::Database) {
+        db.set_secret("group", &self.to_bytes());
+    }
+}
+
+/// One side of the SPAKE2 exchange run while pairing, the code is never sent and
+/// a wrong guess can't be checked offline against what was gossiped
+pub struct PairExchange {
+    state: Spake2<Ed25519Group>,
+    message: Vec<u8>,
+}
+
+impl PairExchange {
+    pub fn start(code: &str) -> Self {
+        let (state, message) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(code.as_bytes()), &Identity::new(b"org-sync pairing"));
+        Self { state, message }
+    }
+
+    pub fn message(&self) -> Vec<u8> {
+        self.message.clone()
+    }
+
+    /// The shared key, which only matches the other side's when both used the same code
+    pub fn finish(self, other: &[u8]) -> Option<PairKey> {
+        let key = self.state.finish(other).ok()?;
+        Some(PairKey { key: key.try_into().ok()? })
+    }
+}
+
+/// Key agreed by a pairing exchange, each side proves it holds the same one before trusting
+pub struct PairKey {
+    key: [u8; KEY_LEN],
+}
+
+impl PairKey {
+    fn mac(&self, label: &[u8], transcript: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&derive(&self.key, b"org-sync pair confirm")).unwrap();
+        mac.update(label);
+        mac.update(transcript);
+        mac
+    }
+
+    pub fn confirm(&self, label: &[u8], transcript: &[u8]) -> Vec<u8> {
+        self.mac(label, transcript).finalize().into_bytes().to_vec()
+    }
+
+    pub fn verify(&self, label: &[u8], transcript: &[u8], tag: &[u8]) -> bool {
+        self.mac(label, transcript).verify_slice(tag).is_ok()
+    }
+
+    pub fn wrap_group(&self, group: &GroupKey) -> Vec<u8> {
+        seal_with(&derive(&self.key, b"org-sync pair wrap"), &group.to_bytes())
+    }
+
+    pub fn unwrap_group(&self, wrapped: &[u8]) -> Option<GroupKey> {
+        GroupKey::from_bytes(&open_with(&derive(&self.key, b"org-sync pair wrap"), wrapped)?)
+    }
+
+    /// Kept with the paired device to hand it later group keys
+    pub fn link(&self) -> LinkKey {
+        LinkKey { key: derive(&self.key, b"org-sync link") }
+    }
+}
+
+/// Key shared with one paired device, a new group key is sealed with it for each device
+pub struct LinkKey {
+    key: [u8; KEY_LEN],
+}
+
+impl LinkKey {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self { key: bytes.try_into().ok()? })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.key.to_vec()
+    }
+
+    pub fn wrap_group(&self, group: &GroupKey) -> Vec<u8> {
+        seal_with(&derive(&self.key, b"org-sync link wrap"), &group.to_bytes())
+    }
+
+    pub fn unwrap_group(&self, wrapped: &[u8]) -> Option<GroupKey> {
+        GroupKey::from_bytes(&open_with(&derive(&self.key, b"org-sync link wrap"), wrapped)?)
+    }
+}
+
+fn group_key_path() -> PathBuf {
+    let mut path = db::config_dir();
+    path.push("group.key");
+    path
+}
+
+/// Load the group key from the database, a device starts in a group of its own
+pub fn load_or_generate(db: &mut db::Database) -> GroupKey {
+    if let Some(bytes) = db.secret("group") {
+        return GroupKey::from_bytes(&bytes).expect("Stored group key to be readable");
+    }
+    // earlier versions kept the key in a file of its own, beside an encrypted database
+    let path = group_key_path();
+    if path.is_file() {
+        let bytes = std::fs::read(&path).expect("Unable to read group key file");
+        let group = GroupKey::from_bytes(&bytes).expect("Group key file to hold a 32 byte key");
+        group.save(db);
+        std::fs::remove_file(&path).expect("Unable to remove group key file");
+        println!("Moved group key from {:?} into the database", path);
+        return group
+    }
+    let group = GroupKey::generate();
+    group.save(db);
+    println!("New group key saved to the database");
+    group
+}
+
+/// Seals database values, and hashes tree keys so file names aren't readable on disk
+#[derive(Clone)]
+pub struct DbKey {
+    keys: Keys,
+}
+
+impl DbKey {
+    fn from_key(key: [u8; KEY_LEN]) -> Self {
+        Self { keys: Keys::new(key, b"org-sync db seal", b"org-sync db key") }
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        seal_with(&self.keys.seal_key, plaintext)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        open_with(&self.keys.seal_key, sealed)
+    }
+
+    pub fn hash_key(&self, key: &[u8]) -> Vec<u8> {
+        self.keys.hash(key)
+    }
+}
+
+// the random database key wrapped with one derived from a passphrase or key file,
+// so changing the passphrase doesn't mean rewriting the database
+#[derive(Serialize, Deserialize, Debug)]
+struct DbKeyFile {
+    salt: Vec<u8>,
+    wrapped: Vec<u8>,
+}
+
+// dir is db::config_dir() outside of tests
+fn db_key_path(dir: &Path) -> PathBuf {
+    dir.join("db.key")
+}
+
+/// Whether the database has been encrypted, it can't be opened without unlocking when it has
+pub fn db_encrypted(dir: &Path) -> bool {
+    db_key_path(dir).is_file()
+}
+
+fn wrapping_key(secret: &[u8], salt: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default().hash_password_into(secret, salt, &mut key)
+        .expect("Argon2 parameters to be valid");
+    key
+}
+
+fn write_db_key(dir: &Path, secret: &[u8], key: &[u8; KEY_LEN]) -> std::io::Result<()> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let keyfile = DbKeyFile {
+        salt: salt.to_vec(),
+        wrapped: seal_with(&wrapping_key(secret, &salt), key),
+    };
+    // written beside and renamed so a crash can't leave the database without a key
+    let path = db_key_path(dir);
+    let tmp = path.with_extension("key.tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(&bendy::serde::to_bytes(&keyfile).unwrap())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, &path)
+}
+
+/// Unwrap the database key, None when the secret is wrong
+pub fn unlock_db_key(dir: &Path, secret: &[u8]) -> Option<DbKey> {
+    let bytes = std::fs::read(db_key_path(dir)).ok()?;
+    let keyfile = bendy::serde::from_bytes::<DbKeyFile>(&bytes).ok()?;
+    let key = open_with(&wrapping_key(secret, &keyfile.salt), &keyfile.wrapped)?;
+    Some(DbKey::from_key(key.try_into().ok()?))
+}
+
+/// A new database key protected by secret, the caller encrypts what is already stored
+pub fn create_db_key(dir: &Path, secret: &[u8]) -> std::io::Result<DbKey> {
+    let key = random_key();
+    write_db_key(dir, secret, &key)?;
+    println!("Database key saved to {:?}", db_key_path(dir));
+    Ok(DbKey::from_key(key))
+}
+
+/// Unlock with the key file named in the config, creating the database key on first use
+pub fn db_key_from_file(dir: &Path, path: &str) -> DbKey {
+    let secret = std::fs::read(path).expect("Unable to read database key file");
+    if !db_encrypted(dir) {
+        return create_db_key(dir, &secret).expect("Unable to write database key");
+    }
+    unlock_db_key(dir, &secret).expect("Database key file doesn't unlock the database")
+}
+
+/// Protect the database key with a new secret, None when the current one is wrong
+pub fn rekey_db(dir: &Path, current: &[u8], new: &[u8]) -> Option<std::io::Result<()>> {
+    let key = unlock_db_key(dir, current)?;
+    Some(write_db_key(dir, new, &key.keys.key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(code: &str, other_code: &str) -> (Option<PairKey>, Option<PairKey>) {
+        let shower = PairExchange::start(code);
+        let enterer = PairExchange::start(other_code);
+        let (shower_message, enterer_message) = (shower.message(), enterer.message());
+        (shower.finish(&enterer_message), enterer.finish(&shower_message))
+    }
+
+    #[test]
+    fn sealed_data_opens_only_with_the_same_key() {
+        let group = GroupKey::generate();
+        let sealed = group.seal(b"* TODO call");
+        assert_eq!(group.open(&sealed).as_deref(), Some(&b"* TODO call"[..]));
+        assert_eq!(GroupKey::generate().open(&sealed), None);
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(group.open(&tampered), None);
+        assert_eq!(group.open(&sealed[..NONCE_LEN - 1]), None);
+    }
+
+    #[test]
+    fn group_key_survives_storage() {
+        let group = GroupKey::generate().rotate(vec![b"revoked".to_vec()]);
+        let stored = GroupKey::from_bytes(&group.to_bytes()).unwrap();
+        assert_eq!(stored.epoch, group.epoch);
+        assert_eq!(stored.exclude, vec![b"revoked".to_vec()]);
+        assert_eq!(stored.open(&group.seal(b"data")).as_deref(), Some(&b"data"[..]));
+        // keys saved by earlier versions are the bare 32 bytes
+        assert!(GroupKey::from_bytes(&[7; KEY_LEN]).is_some());
+    }
+
+    #[test]
+    fn rotated_key_is_newer_and_keeps_exclusions() {
+        let group = GroupKey::generate().rotate(vec![b"a".to_vec()]);
+        let rotated = group.rotate(vec![b"b".to_vec()]);
+        assert!(rotated.newer_than(&group));
+        assert!(!group.newer_than(&rotated));
+        assert_eq!(rotated.exclude, vec![b"b".to_vec(), b"a".to_vec()]);
+    }
+
+    #[test]
+    fn pairing_with_the_same_code_shares_the_group_key() {
+        let (shower, enterer) = exchange("ABCD2345", "ABCD2345");
+        let (shower, enterer) = (shower.unwrap(), enterer.unwrap());
+        let transcript = b"transcript";
+        assert!(enterer.verify(b"shower", transcript, &shower.confirm(b"shower", transcript)));
+        assert!(!enterer.verify(b"enterer", transcript, &shower.confirm(b"shower", transcript)));
+        let group = GroupKey::generate();
+        let received = enterer.unwrap_group(&shower.wrap_group(&group)).unwrap();
+        assert_eq!(received.open(&group.seal(b"data")).as_deref(), Some(&b"data"[..]));
+        // both ends derive the same link key for later hand-overs
+        let handed = enterer.link().unwrap_group(&shower.link().wrap_group(&group)).unwrap();
+        assert_eq!(handed.epoch, group.epoch);
+    }
+
+    #[test]
+    fn pairing_with_a_wrong_code_fails() {
+        let (shower, enterer) = exchange("ABCD2345", "ABCD2346");
+        let (shower, enterer) = (shower.unwrap(), enterer.unwrap());
+        assert!(!enterer.verify(b"shower", b"transcript", &shower.confirm(b"shower", b"transcript")));
+        assert!(enterer.unwrap_group(&shower.wrap_group(&GroupKey::generate())).is_none());
+        assert!(enterer.link().unwrap_group(&shower.link().wrap_group(&GroupKey::generate())).is_none());
+    }
+
+    #[test]
+    fn rekey_keeps_the_database_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        assert!(!db_encrypted(dir));
+        let key = create_db_key(dir, b"old passphrase").unwrap();
+        assert!(db_encrypted(dir));
+        assert!(rekey_db(dir, b"wrong passphrase", b"new passphrase").is_none());
+        rekey_db(dir, b"old passphrase", b"new passphrase").unwrap().unwrap();
+        assert!(unlock_db_key(dir, b"old passphrase").is_none());
+        let unlocked = unlock_db_key(dir, b"new passphrase").unwrap();
+        assert_eq!(unlocked.open(&key.seal(b"value")).as_deref(), Some(&b"value"[..]));
+        assert_eq!(unlocked.hash_key(b"key"), key.hash_key(b"key"));
+    }
+}
@@ -1,38 +1,776 @@
 use sled;
+use serde::{Deserialize, Serialize};
 use std::path::{PathBuf};
+use std::os::unix::fs::DirBuilderExt;
 use crate::netcommand::{Command};
+use crate::types::Version;
+use crate::dht::{DhtEntry, path_to_hash};
+use crate::types::{FilePath, Roots};
+use std::os::unix::fs::MetadataExt;
 use futures::channel::{mpsc};
+use crate::crypto::DbKey;
+use libp2p::PeerId;
 
 #[derive()]
 pub struct Database {
     pub base: sled::Db,
+    pub peers: sled::Tree,
+    pub acks: sled::Tree,
+    pub conflicts: sled::Tree,
+    pub ancestry: sled::Tree,
+    pub contents: sled::Tree,
+    pub history: sled::Tree,
+    pub hashes: sled::Tree,
+    pub trusted: sled::Tree,
+    pub revoked: sled::Tree,
+    // revocations heard from paired devices, waiting for the user to confirm them here
+    // group and network keys, kept here so they are sealed along with everything else
+    pub keys: sled::Tree,
+    // set when the database is encrypted at rest
+    key: Option<DbKey>,
 }
 
-/// Generate new db
-pub fn new() -> Database {
+// longest chain of previous versions followed when checking ancestry
+const ANCESTRY_DEPTH: usize = 256;
+
+/// A file where the local and remote versions both had to be kept
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    pub copy: String,
+    pub local: String,
+    pub remote: String,
+    pub device: String,
+    pub timestamp: i64,
+    // the local file holds merge conflict markers
+    #[serde(default)]
+    pub markers: bool,
+}
+
+// a paired device, with the key group keys are handed to it under
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TrustedPeer {
+    since: i64,
+    link: Vec<u8>,
+}
+
+// peers that acknowledged the tombstone with this timestamp
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Acks {
+    timestamp: i64,
+    peers: Vec<Vec<u8>>,
+}
+
+// hash of a file on disk, reused while the file is unchanged
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedHash {
+    key: Vec<u8>,
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    inode: u64,
+    hash: String,
+}
+
+/// Directory for local state, created owner-only if missing
+pub fn config_dir() -> PathBuf {
     let home = std::env::var("HOME").unwrap();
     let pathstr = "~/.config/org-sync/";
-    let mut dbpath = PathBuf::from(pathstr.replace("~", &home).as_str());
-    if !dbpath.is_dir(){
-        std::fs::create_dir_all(dbpath.as_path()).expect("Unable to make db path");
-        println!("Path to db created {:?}", dbpath.as_path())
+    let path = PathBuf::from(pathstr.replace("~", &home).as_str());
+    if !path.is_dir(){
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(path.as_path())
+            .expect("Unable to make config path");
+        println!("Path to config created {:?}", path.as_path())
     }
+    path
+}
+
+// a value sealed with the key it is stored under, so iterating can give back the key
+fn pack(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut packed = (key.len() as u32).to_be_bytes().to_vec();
+    packed.extend_from_slice(key);
+    packed.extend_from_slice(value);
+    packed
+}
+
+fn unpack(packed: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let len = u32::from_be_bytes(packed.get(..4)?.try_into().ok()?) as usize;
+    let key = packed.get(4..4 + len)?.to_vec();
+    Some((key, packed[4 + len..].to_vec()))
+}
+
+/// Generate new db, values are sealed with key when one is given
+pub fn new(key: Option<DbKey>) -> Database {
+    let mut dbpath = config_dir();
     dbpath.push("db");
-    let base = sled::open(dbpath.as_path()).unwrap();
+    let replaced = dbpath.with_extension("plain");
+    // an encryption run stopped between the two renames
+    if !dbpath.exists() && replaced.exists() {
+        std::fs::rename(&replaced, &dbpath).expect("Unable to restore database");
+    }
+    let mut base = sled::open(dbpath.as_path()).unwrap();
+    if let Some(dbkey) = &key {
+        // the first unlock encrypts what was stored in the clear
+        if !base.open_tree("meta").unwrap().contains_key("encrypted").unwrap_or(false) {
+            encrypt_existing(base, &dbpath, dbkey);
+            base = reopen(&dbpath);
+        }
+    }
+    open(base, key)
+}
+
+// sled's background threads let go of the file lock a little after the last handle is dropped
+fn reopen(dbpath: &std::path::Path) -> sled::Db {
+    for _ in 0..50 {
+        if let Ok(base) = sled::open(dbpath) {
+            return base
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    sled::open(dbpath).unwrap()
+}
+
+/// In-memory db for tests
+#[cfg(test)]
+pub fn temporary() -> Database {
+    open(sled::Config::new().temporary(true).open().unwrap(), None)
+}
+
+fn open(base: sled::Db, key: Option<DbKey>) -> Database {
+    let peers = base.open_tree("known_peers").unwrap();
+    let acks = base.open_tree("tombstone_acks").unwrap();
+    let conflicts = base.open_tree("conflicts").unwrap();
+    let ancestry = base.open_tree("ancestry").unwrap();
+    let contents = base.open_tree("contents").unwrap();
+    let history = base.open_tree("history").unwrap();
+    let hashes = base.open_tree("hash_cache").unwrap();
+    let trusted = base.open_tree("trusted_peers").unwrap();
+    let revoked = base.open_tree("revoked_peers").unwrap();
+    // revocations from paired devices used to wait here for confirmation
+    _ = base.drop_tree("pending_revocations");
+    let keys = base.open_tree("keys").unwrap();
 
     Database {
         base: base,
+        peers: peers,
+        acks: acks,
+        conflicts: conflicts,
+        ancestry: ancestry,
+        contents: contents,
+        history: history,
+        hashes: hashes,
+        trusted: trusted,
+        revoked: revoked,
+        keys: keys,
+        key: key,
+    }
+}
+
+// copied into a fresh database that replaces the old one, since sled keeps
+// overwritten values in its log until the space is reused
+fn encrypt_existing(base: sled::Db, dbpath: &std::path::Path, key: &DbKey) {
+    let fresh_path = dbpath.with_extension("new");
+    _ = std::fs::remove_dir_all(&fresh_path);
+    {
+        let plain = open(base, None);
+        let fresh = open(sled::open(&fresh_path).unwrap(), Some(key.clone()));
+        let mut sealed = vec![];
+        for (from, to) in plain.trees().into_iter().zip(fresh.trees()) {
+            sealed.push(from.name());
+            // history keys are built from the file key, which is hashed once encrypted
+            if from.name() == plain.history.name() {
+                for (_, value) in plain.scan(from) {
+                    if let Ok(version) = bendy::serde::from_bytes::<Version>(&value) {
+                        fresh.put_version(&version);
+                    }
+                }
+                continue
+            }
+            for (key, value) in from.iter().filter_map(|item| item.ok()) {
+                fresh.put(to, &key, &value);
+            }
+        }
+        // DHT records are sealed with the group key already
+        for name in plain.base.tree_names().into_iter().filter(|name| !sealed.contains(name)) {
+            let from = plain.base.open_tree(&name).unwrap();
+            let to = fresh.base.open_tree(&name).unwrap();
+            for (key, value) in from.iter().filter_map(|item| item.ok()) {
+                _ = to.insert(key, value);
+            }
+        }
+        _ = fresh.base.open_tree("meta").unwrap().insert("encrypted", vec![1]);
+        fresh.base.flush().expect("Unable to write encrypted database");
     }
+    let replaced = dbpath.with_extension("plain");
+    std::fs::rename(dbpath, &replaced).expect("Unable to replace database");
+    std::fs::rename(&fresh_path, dbpath).expect("Unable to replace database");
+    std::fs::remove_dir_all(&replaced).expect("Unable to remove unencrypted database");
+    println!("Encrypted the existing database");
 }
 
 impl Database {
+    // every tree is read and written through these so encryption covers all of them
+    // tree keys are keyed hashes when encrypted, the real key is sealed in with the value
+    fn tree_key(&self, key: &[u8]) -> Vec<u8> {
+        match &self.key {
+            Some(dbkey) => dbkey.hash_key(key),
+            None => key.to_vec()
+        }
+    }
+    fn put(&self, tree: &sled::Tree, key: &[u8], value: &[u8]) {
+        self.put_at(tree, self.tree_key(key), key, value);
+    }
+    // for trees scanned by prefix, whose keys are built from tree_key parts
+    fn put_at(&self, tree: &sled::Tree, at: Vec<u8>, key: &[u8], value: &[u8]) {
+        let stored = match &self.key {
+            Some(dbkey) => dbkey.seal(&pack(key, value)),
+            None => value.to_vec()
+        };
+        _ = tree.insert(at, stored);
+    }
+    fn fetch(&self, tree: &sled::Tree, key: &[u8]) -> Option<Vec<u8>> {
+        let stored = tree.get(self.tree_key(key)).ok().flatten()?;
+        match &self.key {
+            Some(dbkey) => unpack(&dbkey.open(&stored)?).map(|(_, value)| value),
+            None => Some(stored.to_vec())
+        }
+    }
+    fn delete(&self, tree: &sled::Tree, key: &[u8]) {
+        _ = tree.remove(self.tree_key(key));
+    }
+    fn has(&self, tree: &sled::Tree, key: &[u8]) -> bool {
+        tree.contains_key(self.tree_key(key)).unwrap_or(false)
+    }
+    fn scan(&self, tree: &sled::Tree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.scan_prefix(tree, &[])
+    }
+    fn scan_prefix(&self, tree: &sled::Tree, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        tree.scan_prefix(prefix)
+            .filter_map(|item| item.ok())
+            .filter_map(|(key, stored)| match &self.key {
+                Some(dbkey) => unpack(&dbkey.open(&stored)?),
+                None => Some((key.to_vec(), stored.to_vec()))
+            })
+            .collect()
+    }
+    fn trees(&self) -> [&sled::Tree; 11] {
+        [&self.base, &self.peers, &self.acks, &self.conflicts, &self.ancestry,
+         &self.contents, &self.history, &self.hashes, &self.trusted, &self.revoked,
+         &self.keys]
+    }
+    pub fn encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
     pub fn insert(&mut self, key: Vec<u8>, val: Vec<u8>){
-         _ = self.base.insert(key, val);
+        self.put(&self.base, &key, &val);
     }
-    pub fn get(&mut self, key: Vec<u8>) -> Option<sled::IVec>{
-        match self.base.get(key) {
-            Ok(result) => result,
-            _ => None
+    pub fn remove(&mut self, key: Vec<u8>){
+        self.delete(&self.base, &key);
+    }
+    pub fn get(&mut self, key: Vec<u8>) -> Option<Vec<u8>>{
+        self.fetch(&self.base, &key)
+    }
+    /// every file entry held in the local db
+    pub fn entries(&mut self) -> Vec<(Vec<u8>, DhtEntry)>{
+        self.scan(&self.base).into_iter()
+            .filter_map(|(key, entry)| Some((key, DhtEntry::from_bytes(entry)?)))
+            .collect()
+    }
+    /// peers seen on the network, used to decide when tombstones can be dropped
+    pub fn add_known_peer(&mut self, peer: Vec<u8>){
+        self.put(&self.peers, &peer, &chrono::Utc::now().timestamp().to_be_bytes());
+    }
+    pub fn known_peers(&mut self) -> Vec<Vec<u8>>{
+        self.scan(&self.peers).into_iter().map(|(peer, _)| peer).collect()
+    }
+    /// forget peers last seen before cutoff or no longer paired, so tombstones
+    /// aren't held forever waiting for their acks
+    pub fn prune_known_peers(&mut self, cutoff: i64){
+        for (peer, seen) in self.scan(&self.peers) {
+            let seen = i64::from_be_bytes(seen.try_into().unwrap_or([0; 8]));
+            if seen < cutoff || !self.has(&self.trusted, &peer) {
+                self.delete(&self.peers, &peer);
+            }
         }
     }
+    /// peers paired with this device, the only ones whose messages and records are accepted
+    pub fn trust_peer(&mut self, peer: Vec<u8>, link: Vec<u8>){
+        let trusted = TrustedPeer { since: chrono::Utc::now().timestamp(), link };
+        self.put(&self.trusted, &peer, &bendy::serde::to_bytes(&trusted).unwrap());
+    }
+    /// None for devices paired before keys were kept per device
+    pub fn link_key(&mut self, peer: &Vec<u8>) -> Option<Vec<u8>>{
+        let stored = self.fetch(&self.trusted, peer)?;
+        bendy::serde::from_bytes::<TrustedPeer>(&stored).ok().map(|trusted| trusted.link)
+    }
+    pub fn is_trusted(&mut self, peer: &Vec<u8>) -> bool{
+        self.has(&self.trusted, peer)
+    }
+    pub fn trusted_peers(&mut self) -> Vec<Vec<u8>>{
+        self.scan(&self.trusted).into_iter().map(|(peer, _)| peer).collect()
+    }
+    /// revoked peers are dropped from the trusted and known sets for good
+    pub fn revoke_peer(&mut self, peer: Vec<u8>, timestamp: i64){
+        self.delete(&self.trusted, &peer);
+        self.delete(&self.peers, &peer);
+        self.put(&self.revoked, &peer, &timestamp.to_be_bytes());
+    }
+    /// lets a revoked device pair again
+    pub fn unrevoke_peer(&mut self, peer: &Vec<u8>){
+        self.delete(&self.revoked, peer);
+    }
+    pub fn is_revoked(&mut self, peer: &Vec<u8>) -> bool{
+        self.has(&self.revoked, peer)
+    }
+    /// revoked peers with the time each was revoked
+    pub fn revoked_peers(&mut self) -> Vec<(Vec<u8>, i64)>{
+        self.scan(&self.revoked).into_iter()
+            .map(|(peer, time)| (peer, i64::from_be_bytes(time.try_into().unwrap_or([0; 8]))))
+            .collect()
+    }
+    /// peers that have acknowledged the tombstone held for a key
+    pub fn secret(&mut self, name: &str) -> Option<Vec<u8>>{
+        self.fetch(&self.keys, name.as_bytes())
+    }
+    pub fn set_secret(&mut self, name: &str, value: &[u8]){
+        self.put(&self.keys, name.as_bytes(), value);
+        _ = self.keys.flush();
+    }
+    pub fn acks(&mut self, key: Vec<u8>, timestamp: i64) -> Vec<Vec<u8>>{
+        match self.fetch(&self.acks, &key).and_then(|acks| bendy::serde::from_bytes::<Acks>(&acks).ok()) {
+            Some(acks) if acks.timestamp == timestamp => acks.peers,
+            _ => vec![]
+        }
+    }
+    /// acks are merged, a newer tombstone starts them again and acks for an older one are ignored
+    pub fn add_ack(&mut self, key: Vec<u8>, timestamp: i64, peer: Vec<u8>){
+        let mut acks = match self.fetch(&self.acks, &key).and_then(|acks| bendy::serde::from_bytes::<Acks>(&acks).ok()) {
+            Some(acks) if acks.timestamp > timestamp => return,
+            Some(acks) if acks.timestamp == timestamp => acks,
+            _ => Acks { timestamp, peers: vec![] }
+        };
+        if !acks.peers.contains(&peer) {
+            acks.peers.push(peer);
+            self.put(&self.acks, &key, &bendy::serde::to_bytes(&acks).unwrap());
+        }
+    }
+    pub fn remove_acks(&mut self, key: Vec<u8>){
+        self.delete(&self.acks, &key);
+    }
+    /// conflicts are keyed by the file key, a newer conflict replaces an older one
+    pub fn add_conflict(&mut self, conflict: Conflict){
+        self.put(&self.conflicts, conflict.path.as_bytes(), &bendy::serde::to_bytes(&conflict).unwrap());
+    }
+    pub fn get_conflict(&mut self, key: Vec<u8>) -> Option<Conflict>{
+        self.fetch(&self.conflicts, &key)
+            .and_then(|conflict| bendy::serde::from_bytes::<Conflict>(&conflict).ok())
+    }
+    pub fn conflicts(&mut self) -> Vec<Conflict>{
+        self.scan(&self.conflicts).into_iter()
+            .filter_map(|(_, conflict)| bendy::serde::from_bytes::<Conflict>(&conflict).ok())
+            .collect()
+    }
+    pub fn remove_conflict(&mut self, key: Vec<u8>){
+        self.delete(&self.conflicts, &key);
+    }
+    /// links a version hash to the hash it was edited from
+    pub fn record_ancestry(&mut self, current: &String, previous: &Option<String>){
+        if let Some(previous) = previous {
+            // a link back to an older version is stale, or a restore handled by record_edit
+            if previous != current && !self.is_ancestor(current, previous) {
+                // a merge keeps its other parents after the one it was edited from
+                let mut parents = self.parents(current);
+                parents.retain(|parent| parent != previous);
+                parents.insert(0, previous.clone());
+                self.put(&self.ancestry, current.as_bytes(), parents.join(",").as_bytes());
+            }
+        }
+    }
+    /// links an entry of the file at key to the version it was edited from, an entry
+    /// bringing back content an older version had comes after the version it replaced
+    pub fn record_edit(&mut self, key: &Vec<u8>, entry: &DhtEntry){
+        let previous = match &entry.previous {
+            Some(previous) if previous != &entry.current && self.is_ancestor(&entry.current, previous) => previous,
+            _ => return self.record_ancestry(&entry.current, &entry.previous)
+        };
+        // an entry older than the version it replaces is one the restore already undid
+        let replaced = self.versions(key).into_iter()
+            .filter(|version| &version.hash == previous)
+            .map(|version| version.timestamp)
+            .max();
+        if replaced.map_or(false, |timestamp| timestamp > entry.timestamp) {
+            return
+        }
+        // versions edited from the restored one now follow what it was edited from
+        let restored = self.parents(&entry.current);
+        for hash in self.ancestors(previous) {
+            let parents = self.parents(&hash);
+            if parents.contains(&entry.current) {
+                let mut moved: Vec<String> = vec![];
+                for parent in parents.into_iter().flat_map(|parent| match parent == entry.current {
+                    true => restored.clone(),
+                    false => vec![parent]
+                }) {
+                    if !moved.contains(&parent) {
+                        moved.push(parent);
+                    }
+                }
+                match moved.is_empty() {
+                    true => self.delete(&self.ancestry, hash.as_bytes()),
+                    false => self.put(&self.ancestry, hash.as_bytes(), moved.join(",").as_bytes())
+                }
+            }
+        }
+        self.put(&self.ancestry, entry.current.as_bytes(), previous.as_bytes());
+    }
+    /// links a merged version to the other version it was merged with
+    pub fn record_merge_parent(&mut self, current: &String, parent: &String){
+        let mut parents = self.parents(current);
+        if parent != current && !parents.contains(parent) && !self.is_ancestor(current, parent) {
+            parents.push(parent.clone());
+            self.put(&self.ancestry, current.as_bytes(), parents.join(",").as_bytes());
+        }
+    }
+    /// versions a hash was made from, the edited one first then any merged in
+    pub fn parents(&mut self, hash: &String) -> Vec<String>{
+        match self.fetch(&self.ancestry, hash.as_bytes()) {
+            Some(parents) => String::from_utf8(parents).unwrap()
+                .split(',')
+                .map(String::from)
+                .collect(),
+            None => vec![]
+        }
+    }
+    /// the hash followed by the versions it was edited from, most recent first
+    pub fn ancestry_chain(&mut self, hash: &String) -> Vec<String>{
+        let mut chain = vec![hash.clone()];
+        let mut current = hash.clone();
+        while chain.len() < ANCESTRY_DEPTH {
+            match self.parents(&current).into_iter().next() {
+                Some(previous) => {
+                    current = previous;
+                    // ancestry recorded by older releases can link back on itself
+                    if chain.contains(&current) {
+                        break
+                    }
+                    chain.push(current.clone());
+                },
+                _ => break
+            }
+        }
+        chain
+    }
+    /// the hash and every known version before it through both sides of merges, nearest first
+    pub fn ancestors(&mut self, hash: &String) -> Vec<String>{
+        let mut found = vec![hash.clone()];
+        let mut next = 0;
+        while next < found.len() && found.len() < ANCESTRY_DEPTH {
+            for parent in self.parents(&found[next]) {
+                if !found.contains(&parent) {
+                    found.push(parent);
+                }
+            }
+            next += 1;
+        }
+        found
+    }
+    /// merged-in parents among the ancestors of a hash, as (merge, parent) pairs
+    pub fn merge_links(&mut self, hash: &String) -> Vec<(String, String)>{
+        let mut links = vec![];
+        for ancestor in self.ancestors(hash) {
+            for parent in self.parents(&ancestor).into_iter().skip(1) {
+                links.push((ancestor.clone(), parent));
+            }
+        }
+        links
+    }
+    /// true if descendant was edited or merged, directly or not, from ancestor
+    pub fn is_ancestor(&mut self, ancestor: &String, descendant: &String) -> bool{
+        self.ancestors(descendant).contains(ancestor)
+    }
+    /// file content by version hash, kept so forks can be merged against their ancestor
+    pub fn store_content(&mut self, hash: &String, data: &[u8]){
+        self.put(&self.contents, hash.as_bytes(), data);
+    }
+    pub fn get_content(&mut self, hash: &String) -> Option<Vec<u8>>{
+        self.fetch(&self.contents, hash.as_bytes())
+    }
+    // history is keyed path, timestamp, hash so a file's versions are one ordered range
+    fn history_prefix(&self, key: &[u8]) -> Vec<u8> {
+        [self.tree_key(key), vec![0]].concat()
+    }
+    fn history_key(&self, key: &[u8], timestamp: i64, hash: &String) -> Vec<u8> {
+        [self.history_prefix(key), timestamp.to_be_bytes().to_vec(), self.tree_key(hash.as_bytes())].concat()
+    }
+    /// keeps the content and metadata of a version of the file at key
+    pub fn record_version(&mut self, key: &Vec<u8>, entry: &DhtEntry, data: &[u8]){
+        self.store_content(&entry.current, data);
+        let version = Version {
+            path: String::from_utf8(key.clone()).unwrap(),
+            hash: entry.current.clone(),
+            previous: entry.previous.clone(),
+            timestamp: entry.timestamp,
+            device: PeerId::from_bytes(&entry.origin).map_or("".into(), |peer| peer.to_base58())
+        };
+        self.put_version(&version);
+    }
+    fn put_version(&self, version: &Version){
+        let at = self.history_key(version.path.as_bytes(), version.timestamp, &version.hash);
+        self.put_at(&self.history, at, version.hash.as_bytes(), &bendy::serde::to_bytes(version).unwrap());
+    }
+    /// versions of the file at key, oldest first
+    pub fn versions(&mut self, key: &Vec<u8>) -> Vec<Version>{
+        let prefix = self.history_prefix(key);
+        let mut versions: Vec<Version> = self.scan_prefix(&self.history, &prefix).into_iter()
+            .filter_map(|(_, version)| bendy::serde::from_bytes::<Version>(&version).ok())
+            .collect();
+        // keys order negative timestamps last, only possible from a skewed clock
+        versions.sort_by_key(|version| version.timestamp);
+        versions
+    }
+    /// a version of the file at key by full hash or unique prefix
+    pub fn find_version(&mut self, key: &Vec<u8>, hash: &String) -> Option<Version>{
+        let mut matches: Vec<Version> = self.versions(key).into_iter()
+            .filter(|version| version.hash.starts_with(hash.as_str()))
+            .collect();
+        if matches.len() == 1 {
+            matches.pop()
+        } else {
+            None
+        }
+    }
+    /// files were keyed by their path under $HOME before roots were named, those
+    /// keys move to the root now holding the file and the rest are dropped
+    pub fn migrate_old_keys(&mut self, roots: &Roots){
+        let home = std::env::var("HOME").unwrap();
+        let rekey = |key: &[u8]| std::str::from_utf8(key).ok()
+            .filter(|key| key.starts_with('/'))
+            .map(|key| roots.from_path(PathBuf::from(format!("{}{}", home, key))).map(|fp| fp.to_key()));
+        for (key, value) in self.scan(&self.base) {
+            match rekey(&key) {
+                Some(Some(new_key)) => {
+                    self.delete(&self.base, &key);
+                    if !self.has(&self.base, new_key.as_bytes()) {
+                        self.put(&self.base, new_key.as_bytes(), &value);
+                    }
+                    println!("Moved {:?} to {:?}", String::from_utf8_lossy(&key), new_key);
+                },
+                Some(None) => {
+                    self.delete(&self.base, &key);
+                    println!("WARNING: {:?} from an older version is under no configured root, forgetting it",
+                             String::from_utf8_lossy(&key));
+                },
+                None => ()
+            }
+        }
+        for (key, value) in self.scan(&self.acks) {
+            if let Some(new_key) = rekey(&key) {
+                self.delete(&self.acks, &key);
+                if let Some(new_key) = new_key {
+                    self.put(&self.acks, new_key.as_bytes(), &value);
+                }
+            }
+        }
+        for mut conflict in self.conflicts() {
+            if let Some(new_key) = rekey(conflict.path.as_bytes()) {
+                self.delete(&self.conflicts, conflict.path.as_bytes());
+                if let Some(new_key) = new_key {
+                    conflict.path = new_key;
+                    self.add_conflict(conflict);
+                }
+            }
+        }
+        // history was keyed by hash alone before it was kept in ranges per file
+        let stored: Vec<_> = self.history.iter().filter_map(|item| item.ok()).collect();
+        for (at, stored) in stored {
+            let value = match &self.key {
+                Some(dbkey) => match dbkey.open(&stored).as_deref().and_then(unpack) {
+                    Some((_, value)) => value,
+                    None => continue
+                },
+                None => stored.to_vec()
+            };
+            let mut version = match bendy::serde::from_bytes::<Version>(&value) {
+                Ok(version) => version,
+                _ => continue
+            };
+            let moved = rekey(version.path.as_bytes());
+            if moved.is_none() && at.starts_with(&self.history_prefix(version.path.as_bytes())) {
+                continue
+            }
+            _ = self.history.remove(&at);
+            match moved {
+                Some(None) => continue,
+                Some(Some(new_key)) => version.path = new_key,
+                None => ()
+            }
+            self.put_version(&version);
+        }
+    }
+    /// hash of the file at fp, hashed again only when its mtime, size or inode change
+    pub fn hash_file(&mut self, fp: &FilePath) -> Option<String>{
+        let metadata = std::fs::metadata(fp.to_path()).ok()?;
+        let key = fp.to_bytes();
+        let cached = self.fetch(&self.hashes, fp.full.as_bytes())
+            .and_then(|cached| bendy::serde::from_bytes::<CachedHash>(&cached).ok());
+        if let Some(cached) = cached {
+            if cached.key == key && cached.mtime == metadata.mtime() && cached.mtime_nsec == metadata.mtime_nsec()
+                && cached.size == metadata.size() && cached.inode == metadata.ino() {
+                return Some(cached.hash)
+            }
+        }
+        let hash = path_to_hash(fp.clone())?;
+        let cached = CachedHash {
+            key,
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size: metadata.size(),
+            inode: metadata.ino(),
+            hash: hash.clone()
+        };
+        self.put(&self.hashes, fp.full.as_bytes(), &bendy::serde::to_bytes(&cached).unwrap());
+        Some(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_merge_and_follow_the_newest_tombstone() {
+        let mut db = temporary();
+        let key = b"notes:/a.org".to_vec();
+        db.add_ack(key.clone(), 10, b"a".to_vec());
+        db.add_ack(key.clone(), 10, b"b".to_vec());
+        db.add_ack(key.clone(), 10, b"a".to_vec());
+        assert_eq!(db.acks(key.clone(), 10), vec![b"a".to_vec(), b"b".to_vec()]);
+        // an ack for an older tombstone doesn't undo the ones held
+        db.add_ack(key.clone(), 5, b"c".to_vec());
+        assert_eq!(db.acks(key.clone(), 10).len(), 2);
+        db.add_ack(key.clone(), 20, b"c".to_vec());
+        assert_eq!(db.acks(key.clone(), 10), Vec::<Vec<u8>>::new());
+        assert_eq!(db.acks(key, 20), vec![b"c".to_vec()]);
+    }
+
+    #[test]
+    fn stale_and_unpaired_peers_are_pruned() {
+        let mut db = temporary();
+        db.trust_peer(b"paired".to_vec(), vec![]);
+        db.add_known_peer(b"paired".to_vec());
+        db.add_known_peer(b"stranger".to_vec());
+        db.prune_known_peers(0);
+        assert_eq!(db.known_peers(), vec![b"paired".to_vec()]);
+        db.prune_known_peers(i64::MAX);
+        assert!(db.known_peers().is_empty());
+    }
+
+    #[test]
+    fn merges_descend_from_both_sides() {
+        let mut db = temporary();
+        let (base, local, remote, merged) = ("base".to_string(), "local".to_string(),
+            "remote".to_string(), "merged".to_string());
+        db.record_ancestry(&local, &Some(base.clone()));
+        db.record_ancestry(&remote, &Some(base.clone()));
+        db.record_ancestry(&merged, &Some(local.clone()));
+        db.record_merge_parent(&merged, &remote);
+        assert!(db.is_ancestor(&remote, &merged));
+        assert!(db.is_ancestor(&base, &merged));
+        assert!(!db.is_ancestor(&merged, &remote));
+        // the edited-from chain stays linear for peers reading it pairwise
+        assert_eq!(db.ancestry_chain(&merged), vec![merged.clone(), local.clone(), base]);
+        assert_eq!(db.merge_links(&merged), vec![(merged.clone(), remote.clone())]);
+        // seeing the merge again from the DHT keeps its second parent
+        db.record_ancestry(&merged, &Some(local));
+        assert!(db.is_ancestor(&remote, &merged));
+    }
+
+    #[test]
+    fn restores_come_after_the_version_they_replace() {
+        let mut db = temporary();
+        let key = b"notes:/a.org".to_vec();
+        let entry = |current: &str, previous: Option<&str>, timestamp| DhtEntry {
+            current: current.into(),
+            previous: previous.map(String::from),
+            timestamp,
+            deleted: false,
+            origin: vec![]
+        };
+        let (first, second, edited) = ("first".to_string(), "second".to_string(), "edited".to_string());
+        for entry in [entry("first", None, 10), entry("second", Some("first"), 20), entry("first", Some("second"), 30)] {
+            db.record_edit(&key, &entry);
+            db.record_version(&key, &entry, b"");
+        }
+        assert!(db.is_ancestor(&second, &first));
+        assert!(!db.is_ancestor(&first, &second));
+        assert_eq!(db.ancestry_chain(&first), vec![first.clone(), second.clone()]);
+        // an edit made from the replaced version at the same time is a fork, not a fast-forward
+        db.record_edit(&key, &entry("edited", Some("second"), 25));
+        assert!(!db.is_ancestor(&first, &edited));
+        assert!(!db.is_ancestor(&edited, &first));
+        assert!(db.ancestors(&edited).contains(&second));
+        // the replaced entry seen again from the DHT or a peer's chain doesn't undo the restore
+        db.record_edit(&key, &entry("second", Some("first"), 20));
+        db.record_ancestry(&second, &Some(first.clone()));
+        assert!(!db.is_ancestor(&first, &second));
+    }
+
+    #[test]
+    fn cached_hashes_follow_size_and_mtime() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_string_lossy().to_string();
+        let fp = FilePath { root: "notes".into(), full: format!("{}/a.org", base), base };
+        let mut db = temporary();
+        let write = |data: &str, modified: Duration| {
+            std::fs::write(fp.to_path(), data).unwrap();
+            std::fs::File::options().write(true).open(fp.to_path()).unwrap()
+                .set_modified(UNIX_EPOCH + modified).unwrap();
+        };
+        let hash = |data: &str| crate::dht::bytes_to_hash(&fp.to_bytes(), data.as_bytes());
+        write("first", Duration::new(1_700_000_000, 100));
+        assert_eq!(db.hash_file(&fp), Some(hash("first")));
+        // same size and mtime is taken as unchanged without reading the file
+        write("fresh", Duration::new(1_700_000_000, 100));
+        assert_eq!(db.hash_file(&fp), Some(hash("first")));
+        // rewritten within the same second
+        write("third", Duration::new(1_700_000_000, 200));
+        assert_eq!(db.hash_file(&fp), Some(hash("third")));
+        write("fourth", Duration::new(1_700_000_000, 200));
+        assert_eq!(db.hash_file(&fp), Some(hash("fourth")));
+        write("fifth!", Duration::new(1_700_000_001, 200));
+        assert_eq!(db.hash_file(&fp), Some(hash("fifth!")));
+        std::fs::remove_file(fp.to_path()).unwrap();
+        assert_eq!(db.hash_file(&fp), None);
+    }
+
+    #[test]
+    fn history_is_kept_per_file_with_its_origin() {
+        let mut db = temporary();
+        let origin = PeerId::random();
+        let entry = |current: &str, timestamp| DhtEntry {
+            current: current.into(),
+            previous: None,
+            timestamp,
+            deleted: false,
+            origin: origin.to_bytes()
+        };
+        let key = b"notes:/a.org".to_vec();
+        db.record_version(&key, &entry("second", 20), b"2");
+        db.record_version(&key, &entry("first", 10), b"1");
+        db.record_version(&b"notes:/a.org.bak".to_vec(), &entry("other", 15), b"3");
+        let versions = db.versions(&key);
+        assert_eq!(versions.iter().map(|version| version.hash.as_str()).collect::<Vec<_>>(), vec!["first", "second"]);
+        assert_eq!(versions[0].device, origin.to_base58());
+        // versions kept under their hash by older releases move into the file's range
+        let old = Version { path: "notes:/b.org".into(), hash: "old".into(), previous: None, timestamp: 5, device: "".into() };
+        db.put(&db.history, b"old", &bendy::serde::to_bytes(&old).unwrap());
+        db.migrate_old_keys(&Roots::default());
+        assert_eq!(db.versions(&b"notes:/b.org".to_vec()).len(), 1);
+        assert_eq!(db.history.len(), 4);
+    }
 }
@@ -3,15 +3,18 @@ use serde::{Deserialize, Serialize};
 use bendy;
 use bendy::encoding::{ToBencode, Error};
 use libp2p::kad::{Kademlia, record::Key, Quorum, Record, QueryId};
-use libp2p::kad::record::store::MemoryStore;
+use crate::dhtstore::SledStore;
 use sha256;
+use sha2::{Digest, Sha256};
+use std::io::Read;
 use chrono::prelude::*;
 
 use crate::types::FilePath;
+use crate::crypto::GroupKey;
 
-pub fn add_to_dht(kademlia: &mut Kademlia<MemoryStore>, file: String, entry: DhtEntry) {
-    let key = Key::new(&file);
-    let value = entry.to_bytes();
+pub fn add_to_dht(kademlia: &mut Kademlia<SledStore>, group: &GroupKey, file: String, entry: DhtEntry) {
+    let key = record_key(group, file.as_bytes());
+    let value = seal_entry(group, file.as_bytes(), &entry);
     let mut record = Record {
         key,
         value,
@@ -22,7 +25,7 @@ pub fn add_to_dht(kademlia: &mut Kademlia<MemoryStore>, file: String, entry: Dht
         .expect("Failed to PUT record");
 }
 
-pub fn delete_from_version(kademlia: &mut Kademlia<MemoryStore>, index: String) {
+pub fn delete_from_version(kademlia: &mut Kademlia<SledStore>, index: String) {
     let base_index = "versions:col:idx";
     let cols = vec!["current_version", "timestamp", "previous_version", "patch", "locations"];
     for c in cols.into_iter() {
@@ -31,19 +34,63 @@ pub fn delete_from_version(kademlia: &mut Kademlia<MemoryStore>, index: String)
     }
 }
 
-pub fn check_col_from_version(kademlia: &mut Kademlia<MemoryStore>, index: String, col: String) -> QueryId {
+pub fn check_col_from_version(kademlia: &mut Kademlia<SledStore>, index: String, col: String) -> QueryId {
     let base_index = "versions:col:idx";
     let key = Key::new(&base_index.replace("col", &col).replace("idx", index.as_ref()));
     kademlia.get_record(key, Quorum::One)
 }
 
 pub fn path_to_hash(path: FilePath) -> Option<String> {
-    // let input = std::fs::File::open(path.to_path()).unwrap();
-    // let reader = std::io::BufReader::new(input);
-    let bytes = std::fs::read(path.to_path()).ok()?;
-    let bpath = path.to_bytes();
-    let comb = [bpath, bytes].concat();
-    Some(sha256::digest_bytes(&comb))
+    let file = std::fs::File::open(path.to_path()).ok()?;
+    reader_to_hash(&path.to_bytes(), file).ok()
+}
+
+/// same hash as bytes_to_hash, streamed so large files aren't held in memory
+pub fn reader_to_hash(key: &[u8], mut reader: impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// hash of file content combined with its key, as stored in DhtEntry
+pub fn bytes_to_hash(key: &[u8], bytes: &[u8]) -> String {
+    let comb = [key, bytes].concat();
+    sha256::digest_bytes(&comb)
+}
+
+/// DHT record key for a file key
+pub fn record_key(group: &GroupKey, key: &[u8]) -> Key {
+    Key::new(&group.hash_key(key))
+}
+
+// the file key travels inside the sealed value so the record key can be a keyed hash
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedEntry {
+    key: Vec<u8>,
+    entry: DhtEntry,
+}
+
+pub fn seal_entry(group: &GroupKey, key: &[u8], entry: &DhtEntry) -> Vec<u8> {
+    let sealed = SealedEntry { key: key.to_vec(), entry: entry.clone() };
+    group.seal(&bendy::serde::to_bytes(&sealed).unwrap())
+}
+
+/// The file key and entry in a record, None for records sealed by another group
+pub fn open_record(group: &GroupKey, record: &Record) -> Option<(Vec<u8>, DhtEntry)> {
+    let sealed = bendy::serde::from_bytes::<SealedEntry>(&group.open(&record.value)?).ok()?;
+    // a value copied under another record key is not accepted
+    if record_key(group, &sealed.key) != record.key {
+        return None
+    }
+    Some((sealed.key, sealed.entry))
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Eq)]
@@ -51,6 +98,12 @@ pub struct DhtEntry {
     pub current: String,
     pub previous: Option<String>,
     pub timestamp: i64,
+    // a tombstone keeps the last known hash in current and the deletion time in timestamp
+    #[serde(default)]
+    pub deleted: bool,
+    // peer id of the device that made this version, empty from older peers
+    #[serde(default)]
+    pub origin: Vec<u8>,
 }
 
 impl DhtEntry {
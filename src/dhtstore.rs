@@ -0,0 +1,303 @@
+// Kademlia record store persisted in sled
+use serde::{Deserialize, Serialize};
+use bendy;
+use libp2p::kad::kbucket;
+use libp2p::kad::record::{Key, ProviderRecord, Record};
+use libp2p::kad::record::store::{Error, RecordStore, Result};
+use libp2p::{Multiaddr, PeerId};
+use std::borrow::Cow;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Configuration for a `SledStore`, mirrors the limits of `MemoryStoreConfig`
+#[derive(Debug, Clone)]
+pub struct SledStoreConfig {
+    pub max_records: usize,
+    pub max_value_bytes: usize,
+    pub max_providers_per_key: usize,
+    pub max_provided_keys: usize,
+}
+
+impl Default for SledStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 1024 * 64,
+            max_value_bytes: 65 * 1024,
+            max_providers_per_key: libp2p::kad::K_VALUE.get(),
+            max_provided_keys: 1024 * 64,
+        }
+    }
+}
+
+/// Record store that keeps DHT records and provider records in sled trees
+/// so the swarm does not forget file state when a daemon restarts
+pub struct SledStore {
+    local_id: PeerId,
+    config: SledStoreConfig,
+    records: sled::Tree,
+    providers: sled::Tree,
+    // sled counts a tree by walking it, so the sizes checked on every insert are kept here
+    record_count: usize,
+    // keys with this peer among their providers
+    provided_count: usize,
+}
+
+// Instant is a monotonic clock and can't be persisted, so expiry is stored as unix seconds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredProvider {
+    key: Vec<u8>,
+    provider: Vec<u8>,
+    expires: Option<u64>,
+    addresses: Vec<Vec<u8>>,
+}
+
+fn instant_to_unix(instant: Instant) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let remaining = instant.saturating_duration_since(Instant::now());
+    (now + remaining).as_secs()
+}
+
+fn unix_to_instant(secs: u64) -> Instant {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let remaining = Duration::from_secs(secs).saturating_sub(now);
+    Instant::now() + remaining
+}
+
+impl StoredRecord {
+    fn from_record(record: &Record) -> Self {
+        Self {
+            key: record.key.to_vec(),
+            value: record.value.clone(),
+            publisher: record.publisher.map(|p| p.to_bytes()),
+            expires: record.expires.map(instant_to_unix),
+        }
+    }
+    fn to_record(self) -> Record {
+        Record {
+            key: Key::from(self.key),
+            value: self.value,
+            publisher: self.publisher.and_then(|p| PeerId::from_bytes(&p).ok()),
+            expires: self.expires.map(unix_to_instant),
+        }
+    }
+}
+
+impl StoredProvider {
+    fn from_record(record: &ProviderRecord) -> Self {
+        Self {
+            key: record.key.to_vec(),
+            provider: record.provider.to_bytes(),
+            expires: record.expires.map(instant_to_unix),
+            addresses: record.addresses.iter().map(|a| a.to_vec()).collect(),
+        }
+    }
+    fn to_record(self) -> Option<ProviderRecord> {
+        Some(ProviderRecord {
+            key: Key::from(self.key),
+            provider: PeerId::from_bytes(&self.provider).ok()?,
+            expires: self.expires.map(unix_to_instant),
+            addresses: self.addresses.into_iter()
+                .filter_map(|a| Multiaddr::try_from(a).ok())
+                .collect(),
+        })
+    }
+}
+
+impl SledStore {
+    pub fn new(local_id: PeerId, db: &sled::Db) -> Self {
+        Self::with_config(local_id, db, Default::default())
+    }
+
+    pub fn with_config(local_id: PeerId, db: &sled::Db, config: SledStoreConfig) -> Self {
+        let records = db.open_tree("kad_records").expect("Unable to open kad records tree");
+        let providers = db.open_tree("kad_providers").expect("Unable to open kad providers tree");
+        let mut store = Self {
+            local_id,
+            config,
+            record_count: records.len(),
+            provided_count: 0,
+            records,
+            providers,
+        };
+        // counted once here, then kept up to date as providers are written
+        store.provided_count = store.providers.iter()
+            .filter_map(|item| item.ok())
+            .filter(|(key, _)| store.provides(&Key::from(key.to_vec())))
+            .count();
+        store
+    }
+
+    fn provides(&self, key: &Key) -> bool {
+        self.read_providers(key).iter().any(|p| p.provider == self.local_id)
+    }
+
+    fn read_providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        match self.providers.get(key.to_vec()) {
+            Ok(Some(bytes)) => bendy::serde::from_bytes::<Vec<StoredProvider>>(&bytes)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|p| p.to_record())
+                .collect(),
+            _ => vec![]
+        }
+    }
+
+    fn write_providers(&mut self, key: &Key, providers: &[ProviderRecord]) {
+        let provided = self.provides(key);
+        if providers.is_empty() {
+            _ = self.providers.remove(key.to_vec());
+        } else {
+            let stored: Vec<StoredProvider> = providers.iter().map(StoredProvider::from_record).collect();
+            _ = self.providers.insert(key.to_vec(), bendy::serde::to_bytes(&stored).unwrap());
+        }
+        match (provided, self.provides(key)) {
+            (false, true) => self.provided_count += 1,
+            (true, false) => self.provided_count -= 1,
+            _ => ()
+        }
+    }
+}
+
+impl<'a> RecordStore<'a> for SledStore {
+    type RecordsIter = std::vec::IntoIter<Cow<'a, Record>>;
+    type ProvidedIter = std::vec::IntoIter<Cow<'a, ProviderRecord>>;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'a, Record>> {
+        let bytes = self.records.get(k.to_vec()).ok()??;
+        let stored = bendy::serde::from_bytes::<StoredRecord>(&bytes).ok()?;
+        Some(Cow::Owned(stored.to_record()))
+    }
+
+    fn put(&'a mut self, r: Record) -> Result<()> {
+        if r.value.len() >= self.config.max_value_bytes {
+            return Err(Error::ValueTooLarge);
+        }
+        let key = r.key.to_vec();
+        if self.record_count >= self.config.max_records
+            && !self.records.contains_key(&key).unwrap_or(false) {
+            return Err(Error::MaxRecords);
+        }
+        let stored = StoredRecord::from_record(&r);
+        if let Ok(None) = self.records.insert(key, bendy::serde::to_bytes(&stored).unwrap()) {
+            self.record_count += 1;
+        }
+        Ok(())
+    }
+
+    fn remove(&'a mut self, k: &Key) {
+        if let Ok(Some(_)) = self.records.remove(k.to_vec()) {
+            self.record_count -= 1;
+        }
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        self.records.iter()
+            .filter_map(|item| item.ok())
+            .filter_map(|(_, bytes)| bendy::serde::from_bytes::<StoredRecord>(&bytes).ok())
+            .map(|stored| Cow::Owned(stored.to_record()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn add_provider(&'a mut self, record: ProviderRecord) -> Result<()> {
+        let mut providers = self.read_providers(&record.key);
+        // the limit is on keys this peer provides, as returned by provided()
+        if record.provider == self.local_id && !providers.iter().any(|p| p.provider == self.local_id)
+            && self.provided_count >= self.config.max_provided_keys {
+            return Err(Error::MaxProvidedKeys);
+        }
+        if let Some(i) = providers.iter().position(|p| p.provider == record.provider) {
+            // in place update of an existing provider record
+            providers[i] = record.clone();
+        } else {
+            // keep the providers closest to the key, as the memory store does
+            let key = kbucket::Key::new(record.key.clone());
+            let provider = kbucket::Key::from(record.provider);
+            if let Some(i) = providers.iter().position(|p| {
+                let pk = kbucket::Key::from(p.provider);
+                provider.distance(&key) < pk.distance(&key)
+            }) {
+                providers.insert(i, record.clone());
+                providers.truncate(self.config.max_providers_per_key);
+            } else if providers.len() < self.config.max_providers_per_key {
+                providers.push(record.clone());
+            }
+        }
+        self.write_providers(&record.key, &providers);
+        Ok(())
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        self.read_providers(key)
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        self.providers.iter()
+            .filter_map(|item| item.ok())
+            .filter_map(|(_, bytes)| bendy::serde::from_bytes::<Vec<StoredProvider>>(&bytes).ok())
+            .flatten()
+            .filter_map(|p| p.to_record())
+            .filter(|p| p.provider == self.local_id)
+            .map(Cow::Owned)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn remove_provider(&'a mut self, key: &Key, provider: &PeerId) {
+        let mut providers = self.read_providers(key);
+        providers.retain(|p| &p.provider != provider);
+        self.write_providers(key, &providers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_limit_counts_distinct_keys() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = SledStoreConfig { max_records: 2, ..Default::default() };
+        let mut store = SledStore::with_config(PeerId::random(), &db, config);
+        let record = |key: &str| Record::new(Key::new(&key), vec![1]);
+        store.put(record("a")).unwrap();
+        store.put(record("b")).unwrap();
+        // replacing a record doesn't count against the limit
+        store.put(record("a")).unwrap();
+        assert!(matches!(store.put(record("c")), Err(Error::MaxRecords)));
+        store.remove(&Key::new(&"a"));
+        store.put(record("c")).unwrap();
+        // counts survive a restart
+        let mut store = SledStore::with_config(PeerId::random(), &db, SledStoreConfig { max_records: 2, ..Default::default() });
+        assert!(matches!(store.put(record("d")), Err(Error::MaxRecords)));
+    }
+
+    #[test]
+    fn provided_limit_counts_keys_this_peer_provides() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let local = PeerId::random();
+        let config = || SledStoreConfig { max_provided_keys: 1, ..Default::default() };
+        let mut store = SledStore::with_config(local, &db, config());
+        let provider = |key: &str, peer| ProviderRecord::new(Key::new(&key), peer, vec![]);
+        // other peers' records don't use up the limit
+        store.add_provider(provider("a", PeerId::random())).unwrap();
+        store.add_provider(provider("b", PeerId::random())).unwrap();
+        store.add_provider(provider("a", local)).unwrap();
+        store.add_provider(provider("a", local)).unwrap();
+        assert_eq!(store.provided().count(), 1);
+        assert!(matches!(store.add_provider(provider("b", local)), Err(Error::MaxProvidedKeys)));
+        // counts survive a restart
+        let mut store = SledStore::with_config(local, &db, config());
+        assert!(matches!(store.add_provider(provider("b", local)), Err(Error::MaxProvidedKeys)));
+        store.remove_provider(&Key::new(&"a"), &local);
+        store.add_provider(provider("b", local)).unwrap();
+        assert_eq!(store.provided().count(), 1);
+    }
+}
@@ -1,8 +1,8 @@
-use libp2p::kad::record::store::MemoryStore;
+use crate::dhtstore::SledStore;
 use libp2p::kad::{Kademlia, PeerRecord, record::Key, Quorum};
 
 // function to check version
-pub fn trigger_check_version(kademlia: &mut Kademlia<MemoryStore>) {
+pub fn trigger_check_version(kademlia: &mut Kademlia<SledStore>) {
     let key = Key::new(&"version".to_string());
     kademlia.get_record(key, Quorum::One);
     // the above will trigger a kademilia event
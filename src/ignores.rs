@@ -0,0 +1,164 @@
+// gitignore-style rules deciding which files under the synced dirs are left alone
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use walkdir::WalkDir;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Name of the per-directory rules file, rules apply to the directory and below
+pub const IGNORE_FILE: &str = ".orgsyncignore";
+
+// editor lock, backup and swap files are never worth syncing, a leading # is escaped as in gitignore
+// conflict copies stay on the device that kept them, see FilePath::conflict_copy for the name
+const DEFAULT_PATTERNS: [&str; 8] = [".#*", "\\#*#", "*~", ".*.sw[px]", "*.tmp", "4913", ".DS_Store",
+                                     "*.conflict-????????-????????-??????*"];
+
+/// Rules are shared by the watcher and the network loop so both see reloads
+pub type SharedRules = Arc<RwLock<IgnoreRules>>;
+
+pub struct IgnoreRules {
+    dirs: Vec<PathBuf>,
+    global: Gitignore,
+    // one matcher per .orgsyncignore, shallowest first so deeper files win
+    local: Vec<(PathBuf, Gitignore)>,
+}
+
+impl IgnoreRules {
+    pub fn new(patterns: Vec<String>, dirs: Vec<PathBuf>) -> Self {
+        let mut builder = GitignoreBuilder::new("/");
+        for pattern in DEFAULT_PATTERNS.iter().map(|p| p.to_string()).chain(patterns) {
+            if let Err(e) = builder.add_line(None, &pattern) {
+                println!("Invalid ignore pattern {:?}: {:?}", pattern, e);
+            }
+        }
+        let global = builder.build().unwrap_or_else(|_| Gitignore::empty());
+        let mut rules = Self { dirs, global, local: vec![] };
+        rules.reload();
+        rules
+    }
+
+    pub fn shared(patterns: Vec<String>, dirs: Vec<PathBuf>) -> SharedRules {
+        Arc::new(RwLock::new(Self::new(patterns, dirs)))
+    }
+
+    /// Reread every .orgsyncignore under the synced dirs
+    pub fn reload(&mut self) {
+        let mut local = vec![];
+        for dir in self.dirs.iter() {
+            for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_name() != IGNORE_FILE || entry.path().is_dir() {
+                    continue
+                }
+                let parent = entry.path().parent().unwrap().to_path_buf();
+                let mut builder = GitignoreBuilder::new(&parent);
+                if let Some(e) = builder.add(entry.path()) {
+                    println!("Error reading {:?}: {:?}", entry.path(), e);
+                }
+                match builder.build() {
+                    Ok(gitignore) => local.push((parent, gitignore)),
+                    Err(e) => println!("Invalid rules in {:?}: {:?}", entry.path(), e)
+                }
+            }
+        }
+        local.sort_by_key(|(dir, _)| dir.components().count());
+        self.local = local;
+    }
+
+    pub fn is_ignore_file(path: &Path) -> bool {
+        path.file_name().map_or(false, |name| name == IGNORE_FILE)
+    }
+
+    /// Whether a path is excluded, the last matching rule wins as in git
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        // the rules themselves are synced so every device filters the same way
+        if Self::is_ignore_file(path) {
+            return false
+        }
+        let is_dir = path.is_dir();
+        let mut ignored = match self.global.matched_path_or_any_parents(path, is_dir) {
+            Match::Ignore(_) => true,
+            _ => false
+        };
+        for (dir, gitignore) in self.local.iter() {
+            if !path.starts_with(dir) {
+                continue
+            }
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => ignored = true,
+                Match::Whitelist(_) => ignored = false,
+                Match::None => {}
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FilePath;
+
+    fn write(path: PathBuf, contents: &str) -> PathBuf {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn editor_files_are_ignored_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::new(vec![], vec![dir.path().to_path_buf()]);
+        assert!(rules.is_ignored(&dir.path().join(".#notes.org")));
+        assert!(rules.is_ignored(&dir.path().join("#notes.org#")));
+        assert!(rules.is_ignored(&dir.path().join("notes.org~")));
+        assert!(!rules.is_ignored(&dir.path().join("notes.org")));
+    }
+
+    #[test]
+    fn conflict_copies_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::new(vec![], vec![dir.path().to_path_buf()]);
+        let base = dir.path().to_string_lossy().to_string();
+        let fp = FilePath { root: "notes".into(), full: format!("{}/a.org", base), base };
+        assert!(rules.is_ignored(fp.conflict_copy("3TvQxWk9").to_path()));
+        assert!(!rules.is_ignored(&dir.path().join("a.conflict-resolution.org")));
+    }
+
+    #[test]
+    fn negation_unignores_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join(IGNORE_FILE), "*.log\n!keep.log\n");
+        let rules = IgnoreRules::new(vec![], vec![dir.path().to_path_buf()]);
+        assert!(rules.is_ignored(&dir.path().join("build.log")));
+        assert!(!rules.is_ignored(&dir.path().join("keep.log")));
+    }
+
+    #[test]
+    fn deeper_rules_files_win() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join(IGNORE_FILE), "*.log\ndrafts/\n");
+        write(dir.path().join("journal").join(IGNORE_FILE), "!*.log\n");
+        let journal = write(dir.path().join("journal").join("today.log"), "");
+        let drafts = write(dir.path().join("drafts").join("idea.org"), "");
+        let rules = IgnoreRules::new(vec![], vec![dir.path().to_path_buf()]);
+        assert!(rules.is_ignored(&dir.path().join("top.log")));
+        assert!(!rules.is_ignored(&journal));
+        // a rule in a subdirectory doesn't reach its parent
+        assert!(rules.is_ignored(&dir.path().join("other.log")));
+        // everything under an ignored directory is ignored
+        assert!(rules.is_ignored(&drafts));
+    }
+
+    #[test]
+    fn config_patterns_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rules = IgnoreRules::new(vec!["*.bak".into()], vec![dir.path().to_path_buf()]);
+        assert!(rules.is_ignored(&dir.path().join("notes.bak")));
+        assert!(!rules.is_ignored(&dir.path().join("notes.txt")));
+        write(dir.path().join(IGNORE_FILE), "*.txt\n");
+        rules.reload();
+        assert!(rules.is_ignored(&dir.path().join("notes.txt")));
+        // the rules files themselves are always synced
+        assert!(!rules.is_ignored(&dir.path().join(IGNORE_FILE)));
+    }
+}
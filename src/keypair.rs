@@ -0,0 +1,71 @@
+// Persistent identity so each machine keeps the same PeerId across restarts
+use libp2p::identity;
+use libp2p::pnet::PreSharedKey;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+
+use crate::db::{self, Database};
+use crate::types::Config;
+
+fn keypair_path() -> PathBuf {
+    let mut path = db::config_dir();
+    path.push("identity");
+    path
+}
+
+/// Load the machine keypair from the config dir, generating and saving it on first start
+pub fn load_or_generate() -> identity::Keypair {
+    let path = keypair_path();
+    if path.is_file() {
+        // the key must only be readable by its owner
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        if mode & 0o077 != 0 {
+            println!("Identity file permissions too open ({:o}), restricting to owner", mode & 0o777);
+            std::fs::set_permissions(&path, Permissions::from_mode(0o600))
+                .expect("Unable to set identity file permissions");
+        }
+        let bytes = std::fs::read(&path).expect("Unable to read identity file");
+        return identity::Keypair::from_protobuf_encoding(&bytes)
+            .expect("Identity file to contain a valid keypair");
+    }
+    let keypair = identity::Keypair::generate_ed25519();
+    let bytes = keypair.to_protobuf_encoding().expect("Keypair to be encodable");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .expect("Unable to create identity file");
+    file.write_all(&bytes).expect("Unable to write identity file");
+    println!("New identity saved to {:?}", path);
+    keypair
+}
+
+/// Pre-shared network key from the config, given inline as hex or as a swarm.key file,
+/// or the one kept in the encrypted database when the config has none
+pub fn load_psk(config: &Config, db: &mut Database) -> Option<PreSharedKey> {
+    let psk = match config.psk.as_ref() {
+        Some(psk) => psk,
+        None => return db.secret("psk").map(|text| String::from_utf8_lossy(&text).parse()
+            .expect("Stored network key to be a swarm.key file"))
+    };
+    let path = PathBuf::from(psk.replace("~", &std::env::var("HOME").unwrap()));
+    let text = if path.is_file() {
+        std::fs::read_to_string(&path).expect("Unable to read network key file")
+    } else {
+        // the same format go-ipfs writes to swarm.key
+        format!("/key/swarm/psk/1.0.0/\n/base16/\n{}", psk)
+    };
+    // running on the open network by mistake would expose every file
+    let key: PreSharedKey = text.trim().parse()
+        .expect("Network key to be 64 hex characters or a swarm.key file");
+    // sealed in the database so the config no longer needs to hold it in the clear
+    let stored = key.to_string();
+    if db.encrypted() && db.secret("psk").as_deref() != Some(stored.as_bytes()) {
+        db.set_secret("psk", stored.as_bytes());
+        println!("Network key stored in the encrypted database, it can be removed from the config");
+    }
+    Some(key)
+}
@@ -9,10 +9,8 @@ use libp2p::kad::{
 };
 use futures::channel::{mpsc};
 use std::error::Error;
-use std::{
-    net::{TcpListener, TcpStream},
-    io::{BufReader},
-};
+use async_std::net::TcpListener;
+use std::time::Duration;
 
 use bendy;
 
@@ -39,7 +37,15 @@ use netevent::NetworkEvent;
 mod netmessages;
 
 mod dht;
+mod dhtstore;
 mod db;
+mod keypair;
+mod merge;
+mod patch;
+mod ignores;
+mod control;
+mod crypto;
+use control::CliInterface;
 
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -48,21 +54,41 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     match args.choice {
         CliArgs::Serve { } => {
-            let (config, dirs, paths) = onload(args);
-            println!("Dir list: {:?}", &dirs);
-            println!("Files list: {:?}", &paths.into_iter().map(|x| x.sub_home()).collect::<Vec<String>>());
+            let (config, roots, paths, rules) = onload(args);
+            let dirs = roots.dirs();
+            println!("Roots: {:?}", &roots.roots);
+            println!("Files list: {:?}", &paths.into_iter().map(|x| x.to_key()).collect::<Vec<String>>());
+
+            // bound first so an encrypted database can be unlocked through it
+            let listener = control::bind_unix().await
+                .expect("Unable to bind control socket");
+            println!("Control socket at {:?}", control::socket_path());
+
+            // once encrypted the database can't be read without the key, whatever the config says
+            let db_key = if config.encrypt_db || crypto::db_encrypted(&db::config_dir()) {
+                Some(match &config.db_key_file {
+                    Some(path) => crypto::db_key_from_file(&db::config_dir(), path),
+                    None => control::wait_for_unlock(&listener).await
+                })
+            } else {
+                None
+            };
 
             // get network objects
             let (mut watcher_sender,
                  mut cli_sender,
                  mut netevent
             ): (mpsc::Sender<Command>,
-                mpsc::Sender<CliCommand>,
+                mpsc::Sender<CliRequest>,
                 NetworkEvent
-            ) = netbase::new().await?;
+            ) = netbase::new(&config, db_key).await?;
 
             // set up file watcher
-            let mut watcher = WatcherSender{sender: watcher_sender};
+            let mut watcher = WatcherSender::new(
+                watcher_sender,
+                rules.clone(),
+                netevent.transfer_pending.clone(),
+                Duration::from_millis(config.debounce_ms));
             spawn(watcher.watch(dirs.clone()));
 
             // Listen on all interfaces and whatever port the OS assigns
@@ -71,6 +97,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .unwrap();
 
             netevent.dirs = dirs;
+            netevent.roots = roots;
+            netevent.rules = rules;
             netevent.startup_check();
             spawn(netevent.run());
             // Read full lines from stdin
@@ -78,29 +106,66 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
             let cliinterface = CliInterface { sender: cli_sender };
 
-            let listener = TcpListener::bind("127.0.0.1:1324").unwrap();
-            cliinterface.run(listener);
+            if let Some(addr) = &config.control_tcp {
+                let listener = TcpListener::bind(addr).await
+                    .expect("Control address to be free, is another daemon running?");
+                println!("Control requests also accepted on {}", addr);
+                spawn(cliinterface.clone().run_tcp(listener));
+            }
+            cliinterface.run_unix(listener).await;
         },
 
         CliArgs::Query {
-            push
+            json,
+            command
         } => {
-            let PushPath::Push {path} = push;
-
-            let comm: CliCommand;
-            if path.is_none() {
-                comm = CliCommand {
-                    Push: "all".into()
-                };
-            } else {
-                comm = CliCommand {
-                    Push: path.unwrap()
-                };
+            let comm: CliCommand = match command {
+                QueryCommand::Status {} => CliCommand::Status,
+                QueryCommand::Peers {} => CliCommand::Peers,
+                QueryCommand::Files {} => CliCommand::Files,
+                QueryCommand::Push {path} => CliCommand::Push { path },
+                QueryCommand::Pull {path} => CliCommand::Pull { path },
+                QueryCommand::Pause {} => CliCommand::Pause,
+                QueryCommand::Resume {} => CliCommand::Resume,
+                QueryCommand::Pair {code} => CliCommand::Pair { code },
+                QueryCommand::Revoke {peer} => CliCommand::Revoke { peer },
+                QueryCommand::Unrevoke {peer} => CliCommand::Unrevoke { peer },
+                QueryCommand::Unlock {key_file, new} => CliCommand::Unlock {
+                    secret: read_secret(key_file, "Passphrase: ", new),
+                    create: new,
+                },
+                QueryCommand::Rekey {key_file, new_key_file} => CliCommand::Rekey {
+                    current: read_secret(key_file, "Current passphrase: ", false),
+                    new: read_secret(new_key_file, "New passphrase: ", true),
+                },
+                QueryCommand::Conflicts {} => CliCommand::Conflicts,
+                QueryCommand::Resolve {path, keep} => CliCommand::Resolve { path, keep },
+                QueryCommand::History {path} => CliCommand::History { path },
+                QueryCommand::Show {path, hash} => CliCommand::Show { path, hash },
+                QueryCommand::Restore {path, hash} => CliCommand::Restore { path, hash },
+            };
+            let config = load_config(&args.config);
+            let reply = match control::query(&config, comm).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    eprintln!("Unable to reach the daemon: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if json {
+                println!("{}", serde_json::to_string(&reply).unwrap());
+                if reply.is_err() {
+                    std::process::exit(1);
+                }
+                return Ok(())
+            }
+            match reply {
+                Ok(response) => println!("{}", response.to_text()),
+                Err(error) => {
+                    eprintln!("{:?}: {}", error.kind, error.message);
+                    std::process::exit(1);
+                }
             }
-            let mut stream = TcpStream::connect("127.0.0.1:1324").unwrap();
-            let bencode = bendy::serde::to_bytes(&comm).unwrap();
-            println!("stream input {:?}", &bencode);
-            stream.write_all(&bencode);
         },
         _ => {}
     }
@@ -108,34 +173,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
 }
 
-#[derive(Clone)]
-pub struct CliInterface {
-    sender: mpsc::Sender<CliCommand>
-}
-impl CliInterface {
-    fn run(mut self, listener: TcpListener) {
-        loop {
-            for stream in listener.incoming() {
-                let stream = stream.unwrap();
-                self.handle_connection(stream);
+// key files are read by the daemon so the path is made absolute,
+// a new passphrase is asked for twice since a typo would lock the database
+fn read_secret(key_file: Option<String>, prompt: &str, confirm: bool) -> DbSecret {
+    if let Some(path) = key_file {
+        match std::fs::canonicalize(&path) {
+            Ok(path) => return DbSecret::KeyFile(path.to_string_lossy().into_owned()),
+            Err(e) => {
+                eprintln!("Unable to find key file {:?}: {}", path, e);
+                std::process::exit(1);
             }
         }
     }
-    fn handle_connection(&mut self, mut stream: TcpStream) {
-        let buf_reader = BufReader::new(&mut stream);
-        let b: Vec<_> = buf_reader
-            .lines()
-            .map(|result| result.unwrap())
-            .take_while(|line| !line.is_empty())
-            .collect();
-        // dbg!("stream input {:?}", &b);
-        let comm: CliCommand = bendy::serde::from_bytes::<CliCommand>(b[0].as_bytes()).unwrap();
-        self.sender.try_send(comm)
-        .expect("Command receiver not to be dropped.")
+    let passphrase = rpassword::prompt_password(prompt).expect("Unable to read passphrase");
+    if passphrase.is_empty() {
+        eprintln!("Passphrase can't be empty");
+        std::process::exit(1);
     }
+    if confirm && rpassword::prompt_password("Repeat passphrase: ").expect("Unable to read passphrase") != passphrase {
+        eprintln!("Passphrases don't match");
+        std::process::exit(1);
+    }
+    DbSecret::Passphrase(passphrase)
 }
 
-
 fn handle_input_line(sender: &mut mpsc::Sender<Command>, line: String) {
     let mut args = line.split(' ');
 
@@ -0,0 +1,173 @@
+// Three-way line merge of diverged files against their common ancestor
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+use std::ops::Range;
+
+// a change from one side expressed against the ancestor's lines
+struct Hunk {
+    remote: bool,
+    ancestor: Range<usize>,
+    lines: Vec<String>,
+}
+
+fn split_lines(text: &str) -> Vec<String> {
+    text.split_inclusive('\n').map(|line| line.to_string()).collect()
+}
+
+fn hunks(ancestor: &[String], side: &[String], remote: bool) -> Vec<Hunk> {
+    capture_diff_slices(Algorithm::Myers, ancestor, side)
+        .into_iter()
+        .map(|op| op.as_tag_tuple())
+        .filter(|(tag, _, _)| *tag != DiffTag::Equal)
+        .map(|(_, old, new)| Hunk {
+            remote,
+            ancestor: old,
+            lines: side[new].to_vec(),
+        })
+        .collect()
+}
+
+// ancestor lines in range with one side's hunks applied
+fn apply(ancestor: &[String], range: &Range<usize>, hunks: &[&Hunk]) -> Vec<String> {
+    let mut out = vec![];
+    let mut pos = range.start;
+    for hunk in hunks {
+        out.extend_from_slice(&ancestor[pos..hunk.ancestor.start]);
+        out.extend(hunk.lines.iter().cloned());
+        pos = hunk.ancestor.end;
+    }
+    out.extend_from_slice(&ancestor[pos..range.end]);
+    out
+}
+
+// markers are Org comment lines so a conflicted file keeps its heading structure
+fn push_block(out: &mut String, lines: &[String]) {
+    for line in lines {
+        out.push_str(line);
+    }
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+/// Merge the local and remote edits of ancestor.
+/// Ok holds a clean merge, Err the merge with conflict markers around overlapping hunks.
+pub fn merge(ancestor: &str, local: &str, remote: &str, remote_name: &str) -> Result<String, String> {
+    let base = split_lines(ancestor);
+    let mut all: Vec<Hunk> = hunks(&base, &split_lines(local), false);
+    all.extend(hunks(&base, &split_lines(remote), true));
+    all.sort_by_key(|hunk| (hunk.ancestor.start, hunk.ancestor.end));
+
+    let mut merged = String::new();
+    let mut conflicted = false;
+    let mut pos = 0;
+    let mut i = 0;
+    while i < all.len() {
+        // group hunks that overlap or touch in the ancestor
+        let mut range = all[i].ancestor.clone();
+        let mut j = i + 1;
+        while j < all.len() && all[j].ancestor.start <= range.end {
+            range.end = std::cmp::max(range.end, all[j].ancestor.end);
+            j += 1;
+        }
+        let group = &all[i..j];
+        for line in &base[pos..range.start] {
+            merged.push_str(line);
+        }
+        let ours: Vec<&Hunk> = group.iter().filter(|hunk| !hunk.remote).collect();
+        let theirs: Vec<&Hunk> = group.iter().filter(|hunk| hunk.remote).collect();
+        let ours_lines = apply(&base, &range, &ours);
+        let theirs_lines = apply(&base, &range, &theirs);
+        if theirs.is_empty() || ours_lines == theirs_lines {
+            push_lines(&mut merged, &ours_lines);
+        } else if ours.is_empty() {
+            push_lines(&mut merged, &theirs_lines);
+        } else {
+            conflicted = true;
+            if !merged.is_empty() && !merged.ends_with('\n') {
+                merged.push('\n');
+            }
+            merged.push_str("# <<<<<<< local\n");
+            push_block(&mut merged, &ours_lines);
+            merged.push_str("# =======\n");
+            push_block(&mut merged, &theirs_lines);
+            merged.push_str(&format!("# >>>>>>> {}\n", remote_name));
+        }
+        pos = range.end;
+        i = j;
+    }
+    for line in &base[pos..] {
+        merged.push_str(line);
+    }
+    if conflicted {
+        Err(merged)
+    } else {
+        Ok(merged)
+    }
+}
+
+fn push_lines(out: &mut String, lines: &[String]) {
+    for line in lines {
+        out.push_str(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separate_edits_merge_cleanly() {
+        let ancestor = "* one\n* two\n* three\n";
+        let local = "* one edited\n* two\n* three\n";
+        let remote = "* one\n* two\n* three edited\n";
+        assert_eq!(merge(ancestor, local, remote, "remote"),
+                   Ok("* one edited\n* two\n* three edited\n".to_string()));
+    }
+
+    #[test]
+    fn the_same_edit_on_both_sides_is_not_a_conflict() {
+        let ancestor = "* one\n* two\n";
+        let edited = "* one\n* two done\n";
+        assert_eq!(merge(ancestor, edited, edited, "remote"), Ok(edited.to_string()));
+    }
+
+    #[test]
+    fn overlapping_edits_are_marked() {
+        let ancestor = "* one\n* two\n* three\n";
+        let local = "* one\n* two local\n* three\n";
+        let remote = "* one\n* two remote\n* three\n";
+        assert_eq!(merge(ancestor, local, remote, "laptop"), Err(
+            "* one\n# <<<<<<< local\n* two local\n# =======\n* two remote\n# >>>>>>> laptop\n* three\n".to_string()));
+    }
+
+    #[test]
+    fn inserts_at_neighbouring_lines_merge() {
+        let ancestor = "* one\n* two\n* three\n";
+        let local = "* one\n** local\n* two\n* three\n";
+        let remote = "* one\n* two\n** remote\n* three\n";
+        assert_eq!(merge(ancestor, local, remote, "remote"),
+                   Ok("* one\n** local\n* two\n** remote\n* three\n".to_string()));
+    }
+
+    #[test]
+    fn inserts_at_the_same_line_are_marked() {
+        let ancestor = "* one\n* two\n";
+        let local = "* one\n** local\n* two\n";
+        let remote = "* one\n** remote\n* two\n";
+        assert!(merge(ancestor, local, remote, "remote").is_err());
+    }
+
+    #[test]
+    fn empty_ancestor() {
+        assert_eq!(merge("", "* same\n", "* same\n", "remote"), Ok("* same\n".to_string()));
+        assert_eq!(merge("", "* local\n", "* remote\n", "remote"), Err(
+            "# <<<<<<< local\n* local\n# =======\n* remote\n# >>>>>>> remote\n".to_string()));
+        assert_eq!(merge("", "", "* remote\n", "remote"), Ok("* remote\n".to_string()));
+    }
+
+    #[test]
+    fn missing_final_newline_keeps_markers_on_their_own_lines() {
+        let merged = merge("* one", "* local", "* remote", "remote").unwrap_err();
+        assert_eq!(merged, "# <<<<<<< local\n* local\n# =======\n* remote\n# >>>>>>> remote\n");
+    }
+}
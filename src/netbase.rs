@@ -2,16 +2,22 @@ use libp2p::gossipsub::MessageId;
 use libp2p::gossipsub::{
     GossipsubMessage, IdentTopic as Topic, MessageAuthenticity, ValidationMode,
 };
-use libp2p::kad::record::store::MemoryStore;
-use libp2p::kad::{Kademlia};
+use crate::dhtstore::SledStore;
+use libp2p::kad::{Kademlia, KademliaBucketInserts, KademliaConfig, KademliaStoreInserts};
 use libp2p::mdns::{Mdns, MdnsConfig};
-use libp2p::{gossipsub, identity, PeerId};
+use libp2p::{gossipsub, PeerId, Transport};
+use libp2p::core::{muxing::StreamMuxerBox, transport::Boxed, upgrade};
+use libp2p::core::either::EitherTransport;
+use libp2p::pnet::{PnetConfig, PreSharedKey};
+use libp2p::tcp::{GenTcpConfig, TcpTransport};
+use libp2p::{dns, identity, mplex, noise, yamux};
 use futures::channel::{mpsc};
 use std::time::{Duration};
 use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
 use std::hash::{Hash, Hasher};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use libp2p::request_response::{
     ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec, RequestResponseEvent,
     RequestResponseMessage, ResponseChannel,
@@ -21,22 +27,56 @@ use crate::netbehaviour::*;
 use crate::netcommand::*;
 use crate::netevent::NetworkEvent;
 use crate::db;
+use crate::keypair;
+use crate::crypto::{self, DbKey};
 use crate::netexchange::*;
+use crate::types::Config;
+
+// tcp with dns, then the private network handshake when a key is set, then the usual
+// noise encryption and yamux/mplex multiplexing as in libp2p's development transport
+async fn build_transport(
+    local_key: &identity::Keypair,
+    psk: Option<PreSharedKey>
+) -> std::io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let tcp = dns::DnsConfig::system(TcpTransport::new(GenTcpConfig::new().nodelay(true))).await?;
+    let base = match psk {
+        Some(psk) => EitherTransport::Left(
+            tcp.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))),
+        None => EitherTransport::Right(tcp)
+    };
+    Ok(base
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseAuthenticated::xx(local_key).unwrap())
+        .multiplex(upgrade::SelectUpgrade::new(
+            yamux::YamuxConfig::default(),
+            mplex::MplexConfig::default(),
+        ))
+        .timeout(Duration::from_secs(20))
+        .boxed())
+}
 
 /// Function for creating new network components
-pub async fn new() -> Result<(
+pub async fn new(config: &Config, db_key: Option<DbKey>) -> Result<(
     mpsc::Sender<Command>,
-    mpsc::Sender<CliCommand>,
+    mpsc::Sender<CliRequest>,
     NetworkEvent), Box<dyn Error>> {
-    // Create a random PeerId
-    // TODO deterministic peerid but specific to machine
-    let local_key = identity::Keypair::generate_ed25519();
+    // Load the machine keypair so the PeerId is stable across restarts
+    let local_key = keypair::load_or_generate();
     let local_peer_id = PeerId::from(local_key.public());
     println!("Local peer id: {:?}", local_peer_id);
 
     // Set up an encrypted TCP Transport over the Mplex and Yamux protocols
-    // let transport = libp2p::development_transport(local_key.clone()).await?;
-    let transport = libp2p::development_transport(local_key.clone()).await?;
+    // peers without the network key fail the handshake before anything else is exchanged
+    // set up database object, DHT records are persisted alongside it
+    // values are sealed when the database is encrypted at rest
+    let mut database: db::Database = db::new(db_key);
+
+    let psk = keypair::load_psk(config, &mut database);
+    match &psk {
+        Some(psk) => println!("Private network, key fingerprint {}", psk.fingerprint()),
+        None => println!("No network key set, any peer on the network can connect")
+    }
+    let transport = build_transport(&local_key, psk).await?;
 
     // Create a Gossipsub topic
     let topic = Topic::new("org-files");
@@ -75,8 +115,12 @@ pub async fn new() -> Result<(
         }
 
         let mdns = Mdns::new(MdnsConfig::default()).unwrap();
-        let store = MemoryStore::new(local_peer_id);
-        let kademlia = Kademlia::new(local_peer_id, store);
+        let store = SledStore::new(local_peer_id, &database.base);
+        // records and routing entries from unpaired peers are checked in the network loop
+        let mut kad_config = KademliaConfig::default();
+        kad_config.set_record_filtering(KademliaStoreInserts::FilterBoth);
+        kad_config.set_kbucket_inserts(KademliaBucketInserts::Manual);
+        let kademlia = Kademlia::with_config(local_peer_id, store, kad_config);
         let ping = libp2p::ping::Behaviour::new(
             libp2p::ping::Config::new().with_keep_alive(true)
                 .with_interval(Duration::from_secs(60))
@@ -92,12 +136,12 @@ pub async fn new() -> Result<(
     };
 
     // for sending and recieving commands across async processes
-    let (watcher_sender, watcher_receiver) = mpsc::channel(0);
-    let (cli_sender, cli_receiver) = mpsc::channel(0);
-    let (command_sender, command_receiver) = mpsc::channel(0);
+    // bounded so a burst of file events waits on the network loop instead of piling up
+    let (watcher_sender, watcher_receiver) = mpsc::channel(64);
+    let (cli_sender, cli_receiver) = mpsc::channel(16);
+    let (command_sender, command_receiver) = mpsc::channel(16);
 
-    // set up database object
-    let database: db::Database = db::new();
+    let group = crypto::load_or_generate(&mut database);
 
     Ok((
         watcher_sender,
@@ -110,8 +154,9 @@ pub async fn new() -> Result<(
             command_sender,
             database,
             topic,
+            Arc::new(Mutex::new(HashMap::new())),
             HashMap::new(),
-            HashMap::new(),
+            group,
         )
     )
     )
@@ -1,7 +1,7 @@
 use libp2p::gossipsub::{
     GossipsubEvent
 };
-use libp2p::kad::record::store::MemoryStore;
+use crate::dhtstore::SledStore;
 use libp2p::kad::{
     Kademlia, KademliaEvent
 };
@@ -18,7 +18,7 @@ use libp2p::request_response::{
 #[behaviour(out_event = "OrgBehaviourEvent")]
 pub struct OrgBehaviour {
     pub gossipsub: gossipsub::Gossipsub,
-    pub kademlia: Kademlia<MemoryStore>,
+    pub kademlia: Kademlia<SledStore>,
     pub mdns: Mdns,
     pub ping: libp2p::ping::Behaviour,
     pub request_response: RequestResponse<FileExchangeCodec>,
@@ -3,7 +3,8 @@ use libp2p::request_response::{ResponseChannel};
 use futures::channel::{oneshot};
 
 use std::error::Error;
-use std::collections::{HashSet};
+use std::collections::{BTreeMap, HashSet};
+use chrono::prelude::*;
 
 use futures::channel::{mpsc};
 use crate::netexchange::*;
@@ -47,12 +48,227 @@ pub enum Command {
     EditFileDelete {
         path: PathBuf
     },
+    EditFileMove {
+        from: PathBuf,
+        to: PathBuf
+    },
+    // a held delete that no move claimed
+    SettleDelete {
+        path: PathBuf
+    },
+    // a pairing code ran out, strangers dialled for it are dropped
+    PairingExpired,
     NewPeer
 }
 
+/// Bumped whenever CliCommand or CliResponse change shape
+pub const CONTROL_VERSION: u32 = 6;
+
+/// One line sent to the control socket
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ControlRequest {
+    pub version: u32,
+    pub command: CliCommand,
+}
+
+/// One line sent back for each ControlRequest
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ControlResponse {
+    pub version: u32,
+    pub result: CliReply,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct CliCommand {
-    pub Push: String,
+pub enum CliCommand {
+    Status,
+    Peers,
+    Files,
+    // publish local versions, all files when no path is given
+    Push { path: Option<String> },
+    // check the DHT for newer versions, all files when no path is given
+    Pull { path: Option<String> },
+    // stop acting on local and remote changes until resumed
+    Pause,
+    Resume,
+    // show a one-time code, or enter one shown on another device
+    Pair { code: Option<String> },
+    // stop trusting a paired device on every peer, or confirm a revocation made elsewhere
+    Revoke { peer: String },
+    // let a revoked device pair again, or dismiss a revocation made elsewhere
+    Unrevoke { peer: String },
+    // open an encrypted database, the daemon waits for this before starting
+    // create is set when the secret was confirmed and may be used for a new key
+    Unlock { secret: DbSecret, create: bool },
+    // protect the database key with a new secret
+    Rekey { current: DbSecret, new: DbSecret },
+    Conflicts,
+    Resolve { path: String, keep: String },
+    History { path: String },
+    Show { path: String, hash: String },
+    Restore { path: String, hash: String },
+}
+
+/// What the database key is protected with, a key file is read by the daemon
+#[derive(Serialize, Deserialize, PartialEq)]
+pub enum DbSecret {
+    Passphrase(String),
+    KeyFile(String),
+}
+
+// the daemon logs commands, a passphrase must not end up in the log
+impl std::fmt::Debug for DbSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbSecret::Passphrase(_) => write!(f, "Passphrase(..)"),
+            DbSecret::KeyFile(path) => write!(f, "KeyFile({:?})", path),
+        }
+    }
+}
+
+impl DbSecret {
+    pub fn to_bytes(&self) -> std::io::Result<Vec<u8>> {
+        match self {
+            DbSecret::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+            DbSecret::KeyFile(path) => std::fs::read(path),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub connected: bool,
+    pub trusted: bool,
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct FileInfo {
+    pub path: String,
+    pub hash: String,
+    pub timestamp: i64,
+    pub deleted: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct VersionInfo {
+    pub hash: String,
+    pub previous: Option<String>,
+    pub timestamp: i64,
+    pub device: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ConflictInfo {
+    pub path: String,
+    pub copy: String,
+    pub device: String,
+    pub markers: bool,
+}
+
+/// Structured results, rendered as text by the client unless it asks for JSON
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum CliResponse {
+    Status {
+        peer_id: String,
+        paused: bool,
+        connected_peers: usize,
+        files: usize,
+        conflicts: usize,
+        pending: usize,
+        roots: BTreeMap<String, String>,
+    },
+    Peers { peers: Vec<PeerInfo> },
+    Files { files: Vec<FileInfo> },
+    History { path: String, versions: Vec<VersionInfo> },
+    Conflicts { conflicts: Vec<ConflictInfo> },
+    Content { path: String, hash: String, content: String },
+    PairCode { code: String, expires_in: u64 },
+    Done { message: String },
+}
+
+impl CliResponse {
+    pub fn to_text(&self) -> String {
+        let time = |timestamp: i64| Utc.timestamp_opt(timestamp, 0).unwrap()
+            .format("%Y-%m-%d %H:%M:%S").to_string();
+        match self {
+            CliResponse::Status { peer_id, paused, connected_peers, files, conflicts, pending, roots } => {
+                let mut lines = vec![
+                    format!("Peer id: {}", peer_id),
+                    format!("State: {}", if *paused { "paused" } else { "syncing" }),
+                    format!("Connected peers: {}", connected_peers),
+                    format!("Files: {}", files),
+                    format!("Conflicts: {}", conflicts),
+                    format!("Waiting for peers: {}", pending),
+                ];
+                lines.extend(roots.iter().map(|(name, dir)| format!("Root {}: {}", name, dir)));
+                lines.join("\n")
+            },
+            CliResponse::Peers { peers } if peers.is_empty() => "No peers".into(),
+            CliResponse::Peers { peers } => peers.iter()
+                .map(|peer| format!("{} {}{}", peer.peer_id,
+                                    if peer.connected { "connected" } else { "known" },
+                                    if peer.trusted { ", paired" } else if peer.revoked { ", revoked" } else { "" }))
+                .collect::<Vec<String>>().join("\n"),
+            CliResponse::Files { files } if files.is_empty() => "No files".into(),
+            CliResponse::Files { files } => files.iter()
+                .map(|file| format!("{} {} {}{}", &file.hash[..12], time(file.timestamp), file.path,
+                                    if file.deleted { " (deleted)" } else { "" }))
+                .collect::<Vec<String>>().join("\n"),
+            CliResponse::History { path, versions } if versions.is_empty() => format!("No history for {:?}", path),
+            CliResponse::History { versions, .. } => versions.iter()
+                .map(|version| format!("{} {} {}", &version.hash[..12], time(version.timestamp), version.device))
+                .collect::<Vec<String>>().join("\n"),
+            CliResponse::Conflicts { conflicts } if conflicts.is_empty() => "No conflicts".into(),
+            CliResponse::Conflicts { conflicts } => conflicts.iter()
+                .map(|conflict| format!("Conflict {:?} with copy {:?} from {:?}", conflict.path, conflict.copy, conflict.device))
+                .collect::<Vec<String>>().join("\n"),
+            CliResponse::Content { content, .. } => content.clone(),
+            CliResponse::PairCode { code, expires_in } => format!(
+                "Enter this code on the other device within {} seconds:\n{}", expires_in, code),
+            CliResponse::Done { message } => message.clone(),
+        }
+    }
+}
+
+/// What went wrong with a control request, sent back to the client
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum CliErrorKind {
+    // the request couldn't be parsed
+    BadRequest,
+    // the client speaks a different CONTROL_VERSION
+    UnsupportedVersion,
+    // no file, version or conflict matched
+    NotFound,
+    // reading or writing a local file failed
+    Io,
+    // the daemon is shutting down
+    Unavailable,
+    // the client runs as a different user than the daemon
+    PermissionDenied,
+    // the database is encrypted and the daemon is waiting for unlock
+    Locked,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct CliError {
+    pub kind: CliErrorKind,
+    pub message: String,
+}
+
+impl CliError {
+    pub fn new(kind: CliErrorKind, message: String) -> Self {
+        Self { kind, message }
+    }
+}
+
+pub type CliReply = Result<CliResponse, CliError>;
+
+/// A control request with the channel its reply goes back on
+#[derive(Debug)]
+pub struct CliRequest {
+    pub command: CliCommand,
+    pub sender: oneshot::Sender<CliReply>,
 }
 
 pub struct Commanders {
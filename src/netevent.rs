@@ -1,33 +1,101 @@
 use crate::netbehaviour::{OrgBehaviour, OrgBehaviourEvent};
-use crate::netcommand::{Command, CliCommand};
+use crate::netcommand::{Command, CliCommand, CliError, CliErrorKind, CliReply, CliRequest, CliResponse,
+                        ConflictInfo, DbSecret, FileInfo, PeerInfo, VersionInfo};
 use crate::db::*;
 use crate::netmessages as nm;
-use crate::types::{FilePath};
+use crate::types::{FilePath, Roots};
 use crate::dht::*;
 use crate::netexchange::*;
+use crate::merge;
+use crate::patch;
+use crate::ignores::{IgnoreRules, SharedRules};
+use crate::crypto::{self, GroupKey, LinkKey, PairExchange, PairKey};
 use walkdir::{WalkDir};
 use std::path::{PathBuf};
 use chrono::prelude::*;
 use futures::channel::{mpsc};
 use futures::{prelude::*, select};
-use libp2p::{Swarm, PeerId};
+use libp2p::{Multiaddr, Swarm, PeerId};
 use libp2p::swarm::{SwarmEvent};
 use libp2p::gossipsub::error::GossipsubHandlerError;
 use libp2p::mdns::{MdnsEvent};
 use libp2p::gossipsub::{GossipsubEvent, IdentTopic as Topic};
-use libp2p::kad::record::store::MemoryStore;
 use libp2p::kad::{
     record::Key, AddProviderOk, Kademlia, KademliaEvent, PeerRecord, PutRecordOk, QueryResult,
     Quorum, Record,
 };
-use libp2p::request_response::{RequestResponseEvent, RequestResponseMessage};
-use std::collections::HashMap;
+use libp2p::kad::record::store::RecordStore;
+use libp2p::kad::InboundRequest;
+use libp2p::swarm::NetworkBehaviour;
+use rand::Rng;
+use std::time::Instant;
+use libp2p::request_response::{RequestId, RequestResponseEvent, RequestResponseMessage, ResponseChannel};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use async_std::io;
 use std::error::Error;
 use std::slice::Windows;
 use libp2p::core::either::EitherError;
 use void;
 
+// patches larger than this are left out of gossip, the file is fetched instead
+const MAX_PATCH_BYTES: usize = 32 * 1024;
+// a removed file is held this long in case it turns up elsewhere as a move
+const MOVE_WINDOW: std::time::Duration = std::time::Duration::from_secs(2);
+// how long a pairing code can be used for
+const PAIR_WINDOW: std::time::Duration = std::time::Duration::from_secs(300);
+// pairing code alphabet, without characters easily misread for each other
+const PAIR_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+// seconds a paired peer can go unseen before tombstones stop waiting for its ack
+const PEER_TTL: i64 = 30 * 24 * 60 * 60;
+
+// how long a write by the network loop waits for the watcher to see it
+const WRITE_WINDOW: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A file the network loop wrote, the watcher skips its event while the file
+/// still holds exactly these bytes
+#[derive(Debug, Clone)]
+pub struct ExpectedWrite {
+    pub digest: String,
+    pub expires: Instant,
+}
+
+/// Full paths of files written by the network loop, shared with the watcher
+pub type SharedTransfers = Arc<Mutex<HashMap<String, ExpectedWrite>>>;
+
+/// Startup work held until peers are connected
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncAction {
+    // local version recorded while offline, put in the DHT and gossip it
+    Publish { added: bool },
+    // ask the DHT for the current version
+    Fetch,
+    // local deletion recorded while offline, put the tombstone in the DHT and gossip it
+    Remove,
+}
+
+/// How a received file is applied once the transfer completes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transfer {
+    // overwrite the local file and db entry
+    Update,
+    // update if the received version descends from the local one, otherwise a fork
+    Reconcile,
+    // keep the local file and write the remote version beside it
+    Conflict,
+}
+
+/// How a remote version relates to the local one
+#[derive(Debug, Clone, PartialEq)]
+pub enum Descent {
+    Same,
+    // edited or merged from the local version
+    Newer,
+    // the local version was edited from it, peers still announcing it missed an update
+    Stale,
+    Fork,
+}
+
 // This is a behemoth of a data structure
 // Important lifecycles to understand
 // 1. detect add/change/delete file (watch reciever) -> send message to peers -> update DHT
@@ -36,27 +104,54 @@ use void;
 pub struct NetworkEvent {
     pub swarm: Swarm<OrgBehaviour>,
     pub watchreceiver: mpsc::Receiver<Command>,
-    pub clireceiver: mpsc::Receiver<CliCommand>,
+    pub clireceiver: mpsc::Receiver<CliRequest>,
     pub commandreceiver: mpsc::Receiver<Command>,
     pub commandsender: mpsc::Sender<Command>,
     pub db: Database,
     pub topic: Topic,
-    pub transfer_pending: HashMap<String, String>,
+    pub transfer_pending: SharedTransfers,
     pub key_2_filepath: HashMap<Vec<u8>, FilePath>,
+    // file key, kind of transfer and the hash asked for
+    pub pending_requests: HashMap<RequestId, (Vec<u8>, Transfer, String)>,
     pub dirs: Vec<PathBuf>,
+    pub roots: Roots,
+    pub rules: SharedRules,
+    // content digest of recently removed files, not yet tombstoned
+    pub recent_removed: HashMap<String, PathBuf>,
+    pub sync_pending: HashMap<Vec<u8>, SyncAction>,
+    // set from the control socket, local and remote changes are left until resumed
+    pub paused: bool,
+    // watcher events held while paused, replayed in order on resume
+    pub held_events: Vec<Command>,
+    // code shown to pair another device with this one
+    pub pair_code: Option<(String, Instant)>,
+    // code entered here, waiting for the device showing it to accept
+    pub pair_entered: Option<(String, Instant)>,
+    // our half of the key exchange for the entered code
+    pub pair_exchange: Option<PairExchange>,
+    // device whose request used up the shown code, trusted once it confirms the
+    // exchanged key along with the transcript both confirmations cover
+    pub pair_accepted: Option<(PeerId, PairKey, Vec<u8>, Instant)>,
+    // shared by paired devices, seals what is sent to peers
+    pub group: GroupKey,
+    // DHT record keys asked for, mapped back to file keys
+    pub dht_keys: HashMap<Vec<u8>, Vec<u8>>,
+    // unpaired devices found over mdns, which forgets them once they are disconnected
+    pub strangers: HashMap<PeerId, Multiaddr>,
 }
 
 impl NetworkEvent {
     pub fn new (
         swarm: Swarm<OrgBehaviour>,
         watchreceiver: mpsc::Receiver<Command>,
-        clireceiver: mpsc::Receiver<CliCommand>,
+        clireceiver: mpsc::Receiver<CliRequest>,
         commandreceiver: mpsc::Receiver<Command>,
         commandsender: mpsc::Sender<Command>,
         db: Database,
         topic: Topic,
-        transfer_pending: HashMap<String, String>,
-        key_2_filepath: HashMap<Vec<u8>, FilePath>
+        transfer_pending: SharedTransfers,
+        key_2_filepath: HashMap<Vec<u8>, FilePath>,
+        group: GroupKey
     ) -> Self {
         Self {
             swarm,
@@ -68,7 +163,21 @@ impl NetworkEvent {
             topic,
             transfer_pending,
             key_2_filepath,
-            dirs: vec![]
+            pending_requests: HashMap::new(),
+            dirs: vec![],
+            roots: Roots::default(),
+            rules: IgnoreRules::shared(vec![], vec![]),
+            recent_removed: HashMap::new(),
+            sync_pending: HashMap::new(),
+            paused: false,
+            held_events: vec![],
+            pair_code: None,
+            pair_entered: None,
+            pair_exchange: None,
+            pair_accepted: None,
+            group,
+            dht_keys: HashMap::new(),
+            strangers: HashMap::new()
         }
     }
 
@@ -80,10 +189,17 @@ impl NetworkEvent {
                     self.handle_swarm(event).await;
                 },
                 watch = self.watchreceiver.select_next_some() => {
-                    self.handle_watch(watch).await;
+                    // replaying keeps moves as moves, a rescan would only see deletes and adds
+                    if self.paused {
+                        self.held_events.push(watch);
+                    } else {
+                        self.handle_watch(watch).await;
+                    }
                 },
                 cli = self.clireceiver.select_next_some() => {
-                    self.handle_cli(cli).await;
+                    let reply = self.handle_cli(cli.command).await;
+                    // the client may have hung up already
+                    _ = cli.sender.send(reply);
                 }
                 command = self.commandreceiver.select_next_some() => {
                     self.handle_command(command).await;
@@ -92,21 +208,86 @@ impl NetworkEvent {
         }
     }
 
+    // compares files on disk with the local db, edits made while the daemon was off
+    // become new versions stamped with the file's modification time
     fn check_local_db(&mut self) {
-        // checks local files against local db and loads into db_loaded hashmap
         let files = self.get_files_from_dirs(self.dirs.clone());
-        for file in files {
-            let hash = path_to_hash(file.clone());
-            // sha256 hash is the version UID
-            // _ = self.db.insert(key, val);
-       }
-
+        let local_peer_id = *self.swarm.local_peer_id();
+        let rules = self.rules.clone();
+        // deletes held for move detection are settled by their own timer
+        let held: HashSet<PathBuf> = self.recent_removed.values().cloned().collect();
+        let found = scan_local(&mut self.db, &self.roots, &rules.read().unwrap(), files, &held, &local_peer_id);
+        self.sync_pending.extend(found);
     }
 
+    // records the DHT store kept from the last run that disagree with the local db
+    // are checked against peers
     fn check_dht_vs_local(&mut self) {
-        // checks DHT and loads into db_loaded hashmap
-        let files = self.get_files_from_dirs(self.dirs.clone());
-        // get peers most recent timestamp from
+        for (key, entry) in self.db.entries() {
+            if self.sync_pending.contains_key(&key) {
+                continue
+            }
+            let record_key = record_key(&self.group, &key);
+            let stored = self.swarm.behaviour_mut().kademlia.store_mut()
+                .get(&record_key)
+                .and_then(|record| open_record(&self.group, &record))
+                .map(|(_, entry)| entry);
+            if stored.map_or(true, |stored| stored != entry) {
+                self.sync_pending.insert(key, SyncAction::Fetch);
+            }
+        }
+    }
+
+    // publishes and fetches queued at startup, run once peers are connected
+    pub fn sync(&mut self) {
+        let pending: Vec<(Vec<u8>, SyncAction)> = self.sync_pending.drain().collect();
+        for (key, action) in pending {
+            match action {
+                SyncAction::Fetch => {
+                    self.dht_get(&key);
+                },
+                SyncAction::Remove => {
+                    let tombstone = match self.db.get(key.clone())
+                        .and_then(|entry| DhtEntry::from_bytes(entry.to_vec())) {
+                        Some(entry) if entry.deleted => entry,
+                        _ => continue
+                    };
+                    add_to_dht(
+                        &mut self.swarm.behaviour_mut().kademlia,
+                        &self.group,
+                        String::from_utf8(key.clone()).unwrap(),
+                        tombstone.clone());
+                    let peerid = self.swarm.local_peer_id().to_bytes();
+                    self.publish(nm::Messages::Removed {
+                        path: String::from_utf8(key).unwrap(),
+                        hash: tombstone.current,
+                        timestamp: tombstone.timestamp,
+                        peerid
+                    });
+                },
+                SyncAction::Publish { added } => {
+                    let entry = match self.db.get(key.clone())
+                        .and_then(|entry| DhtEntry::from_bytes(entry.to_vec())) {
+                        Some(entry) if !entry.deleted => entry,
+                        _ => continue
+                    };
+                    add_to_dht(
+                        &mut self.swarm.behaviour_mut().kademlia,
+                        &self.group,
+                        String::from_utf8(key.clone()).unwrap(),
+                        entry.clone());
+                    if added {
+                        let peerid = self.swarm.local_peer_id().to_bytes();
+                        self.publish(nm::Messages::Added {
+                            path: String::from_utf8(key).unwrap(),
+                            peerid
+                        });
+                    } else {
+                        self.publish_changed(key, &entry);
+                    }
+                }
+            }
+        }
     }
 
     fn update_filepaths(&mut self) {
@@ -117,59 +298,246 @@ impl NetworkEvent {
     }
 
     pub fn startup_check(&mut self) {
-
+        self.db.migrate_old_keys(&self.roots);
+        self.update_filepaths();
+        self.check_local_db();
+        self.check_dht_vs_local();
+        println!("Startup check queued {:?} files to sync", self.sync_pending.len());
+        for (peer, _) in self.db.revoked_peers() {
+            if let Ok(peer) = PeerId::from_bytes(&peer) {
+                self.swarm.ban_peer_id(peer);
+            }
+        }
+        if self.db.trusted_peers().is_empty() {
+            println!("No paired devices, run `org-syncer query pair` to pair one");
+        }
     }
 
     pub fn add_peer_check(&mut self) {
         self.update_filepaths();
+        self.sync();
+        // peers that were offline when a device was revoked hear about it now
+        for (peer, timestamp) in self.db.revoked_peers() {
+            let peerid = self.swarm.local_peer_id().to_bytes();
+            self.publish(nm::Messages::Revoked { peer, timestamp, peerid });
+        }
         let files = self.get_files_from_dirs(self.dirs.clone());
         // do get request for each file, will sync local and dht db
         println!("Number of peers {:?}", self.swarm.connected_peers().collect::<Vec<&PeerId>>().len());
         for file in files {
             // this message runs GET on filepath in message
-            self.swarm.behaviour_mut().kademlia.get_record(
-                Key::new(&file.to_bytes()), Quorum::One);
+            self.dht_get(&file.to_bytes());
             // send startup filecheck message
-            if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(
-                self.topic.clone(),
-                nm::to_bytes(nm::Messages::FileCheck {
-                    filepath: file.struct_to_bytes(),
-                    timestamp: chrono::Utc::now().timestamp()
-                })
-            ) {
-                println!("Publish error: {:?}", e);
-            };
+            self.publish(nm::Messages::FileCheck {
+                filepath: file.to_bytes(),
+                timestamp: chrono::Utc::now().timestamp()
+            });
         }
         // Ensure base folders in config are available in home
     }
 
+    // noted before writing so the watcher can tell the write from a local edit
+    fn expect_write(&self, path: &str, data: &[u8]) {
+        let mut expected = self.transfer_pending.lock().unwrap();
+        // writes the watcher never reported, such as to ignored files, are dropped here
+        expected.retain(|_, write| write.expires > Instant::now());
+        expected.insert(path.to_string(), ExpectedWrite {
+            digest: sha256::digest(data),
+            expires: Instant::now() + WRITE_WINDOW
+        });
+    }
+
     /// FILE TRANSFER REQUEST RESPONSE
+    // the hash asked for is kept with the request and checked against the response
     fn request_file(&mut self, peer: &PeerId, key:&Vec<u8>, entry: DhtEntry) {
-        let fp = self.key_2_filepath.get(key).unwrap();
-        println!("requesting {:?} from {:?}", fp.sub_home() , peer);
-        // self.swarm.behaviour_mut().
+        self.request_transfer(peer, key, entry, Transfer::Update);
+    }
+
+    fn request_transfer(&mut self, peer: &PeerId, key:&Vec<u8>, entry: DhtEntry, transfer: Transfer) {
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return
+        };
+        println!("requesting {:?} from {:?}", fp.to_key() , peer);
+        let request = RequestData {
+            key: key.clone(),
+            hash: entry.current.clone(),
+            any_version: transfer != Transfer::Update
+        };
+        let request_id = self.swarm.behaviour_mut().request_response
+            .send_request(peer, FileRequest(self.group.seal(&request.to_bytes())));
+        self.pending_requests.insert(request_id, (key.clone(), transfer, entry.current));
+    }
+
+    fn handle_message_request(
+        &mut self,
+        peer: PeerId,
+        request: FileRequest,
+        channel: ResponseChannel<FileResponse>
+    ) {
+        let request = match self.group.open(&request.0).and_then(RequestData::from_bytes) {
+            Some(request) => request,
+            None => {
+                println!("Malformed file request from {:?}", peer);
+                return
+            }
+        };
+        let fp = match self.roots.from_key(&request.key) {
+            Some(fp) => fp,
+            None => return
+        };
+        if self.ignored(&fp) {
+            println!("Not serving ignored file {:?}", fp.to_key());
+            return
+        }
+        let entry = match self.db.get(request.key.clone()) {
+            Some(entry) => DhtEntry::from_bytes(entry.to_vec()).unwrap(),
+            None => {
+                println!("No local entry for requested file {:?}", fp.to_key());
+                return
+            }
+        };
+        let data = match std::fs::read(fp.to_path()) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Unable to read requested file {:?}: {:?}", fp.to_key(), e);
+                return
+            }
+        };
+        // only serve content that matches what the local db says we have
+        if bytes_to_hash(&request.key, &data) != entry.current {
+            println!("Local db out of date for {:?}, not serving", fp.to_key());
+            return
+        }
+        // the requester goes back to the DHT for the version we have now
+        if entry.current != request.hash && !request.any_version {
+            println!("Requested version of {:?} is no longer current, not serving", fp.to_key());
+            return
+        }
+        let ancestry = self.db.ancestry_chain(&entry.current);
+        let merges = self.db.merge_links(&entry.current);
+        let response = ResponseData {
+            metadata: entry,
+            data,
+            ancestry,
+            merges
+        };
+        if self.swarm.behaviour_mut().request_response
+            .send_response(channel, FileResponse(self.group.seal(&response.to_bytes()))).is_err() {
+            println!("Failed to respond to {:?} for {:?}", peer, fp.to_key());
+        }
     }
 
-    fn handle_message_request(&mut self, request: FileRequest ) {
+    fn handle_message_response(&mut self, peer: PeerId, request_id: RequestId, response: FileResponse ) {
+        let (key, transfer, expected) = match self.pending_requests.remove(&request_id) {
+            Some(pending) => pending,
+            None => {
+                println!("Response for unknown request {:?}", request_id);
+                return
+            }
+        };
+        self.apply_response(peer, key, transfer, expected, response);
+    }
 
+    fn apply_response(&mut self, peer: PeerId, key: Vec<u8>, transfer: Transfer, expected: String, response: FileResponse) {
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return
+        };
+        let response = match self.group.open(&response.0).and_then(ResponseData::from_bytes) {
+            Some(response) => response,
+            None => {
+                println!("Malformed file response for {:?}", fp.to_key());
+                return
+            }
+        };
+        let hash = bytes_to_hash(&key, &response.data);
+        if hash != response.metadata.current {
+            println!("Hash mismatch for received file {:?}, discarding", fp.to_key());
+            return
+        }
+        // only reconciling and conflict copies take a version other than the one asked for
+        if transfer == Transfer::Update && hash != expected {
+            println!("Received a different version of {:?} than requested, checking the DHT again", fp.to_key());
+            self.dht_get(&key);
+            return
+        }
+        for link in response.ancestry.windows(2) {
+            self.db.record_ancestry(&link[0], &Some(link[1].clone()));
+        }
+        self.db.record_edit(&key, &response.metadata);
+        for (merge, parent) in &response.merges {
+            self.db.record_merge_parent(merge, parent);
+        }
+        self.db.record_version(&key, &response.metadata, &response.data);
+        if transfer == Transfer::Reconcile {
+            if let Some(local_hash) = self.db.hash_file(&fp) {
+                match descent(&mut self.db, &local_hash, &hash) {
+                    Descent::Stale => {
+                        println!("Keeping local {:?}, received version is older", fp.to_key());
+                        self.announce_local(key);
+                        return
+                    },
+                    Descent::Fork => {
+                        println!("Fork detected for {:?}, neither version descends from the other", fp.to_key());
+                        if !self.merge_fork(key.clone(), peer, &local_hash, &response) {
+                            self.write_conflict_copy(key, peer, response, false);
+                        }
+                        return
+                    },
+                    _ => ()
+                }
+            }
+            println!("Fast-forwarding {:?} to received version", fp.to_key());
+        }
+        if transfer == Transfer::Conflict {
+            self.write_conflict_copy(key, peer, response, false);
+            return
+        }
+        self.write_received(key, response);
     }
-    fn handle_message_response(&mut self, response: FileResponse ) {
 
+    fn write_received(&mut self, key: Vec<u8>, response: ResponseData) {
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return
+        };
+        if let Some(parent) = fp.to_path().parent() {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                println!("Unable to create directory for {:?}: {:?}", fp.to_key(), e);
+                return
+            }
+        }
+        self.expect_write(&fp.full, &response.data);
+        if let Err(e) = std::fs::write(fp.to_path(), &response.data) {
+            println!("Unable to write received file {:?}: {:?}", fp.to_key(), e);
+            return
+        }
+        self.db.insert(key.clone(), response.metadata.to_bytes());
+        println!("Received and wrote {:?}", fp.to_key());
+        self.key_2_filepath.insert(key, fp);
     }
 
     fn handle_request_response(&mut self, event: RequestResponseEvent<FileRequest, FileResponse>) {
         match event {
             RequestResponseEvent::Message {peer, message} => {
                 match message {
-                    RequestResponseMessage::Request { request, .. } => {
-                        self.handle_message_request(request);
+                    RequestResponseMessage::Request { request, channel, .. } => {
+                        // dropping the channel tells the peer the request failed
+                        if !self.is_trusted(&peer) {
+                            println!("Refused file request from unpaired peer {:?}", peer);
+                            return
+                        }
+                        self.handle_message_request(peer, request, channel);
                     }
-                    RequestResponseMessage::Response { response, .. } => {
-                        self.handle_message_response( response );
+                    RequestResponseMessage::Response { request_id, response } => {
+                        self.handle_message_response(peer, request_id, response);
                     }
                 }
             },
-            RequestResponseEvent::OutboundFailure {error, ..} => {
+            RequestResponseEvent::OutboundFailure {request_id, error, ..} => {
+                // a later check retries the transfer
+                self.pending_requests.remove(&request_id);
                 println!("{:?}", error);
             },
             RequestResponseEvent::InboundFailure {error, ..} => {
@@ -190,22 +558,48 @@ impl NetworkEvent {
         //
         match results {
             Ok(libp2p::kad::GetRecordOk {records, ..}) => {
-                let recvec: Vec<PeerRecord> = records.clone().into_iter().collect();
+                // records from our own store have no peer
+                // records sealed by another group are dropped
+                let recvec: Vec<(PeerRecord, Vec<u8>, DhtEntry)> = records.clone().into_iter()
+                    .filter_map(|record| {
+                        if !record.peer.map_or(true, |peer| self.is_trusted(&peer))
+                            || record.record.publisher.map_or(false, |publisher| self.is_revoked(&publisher)) {
+                            return None
+                        }
+                        let (key, entry) = open_record(&self.group, &record.record)?;
+                        Some((record, key, entry))
+                    })
+                    .collect();
                 println!("Records collected: {:?}", recvec.len());
-                let all_same: bool = recvec.windows(2).all(|w| w[0] == w[1]);
-                let max_record = recvec.iter().max_by_key(
-                    |w| DhtEntry::from_bytes(w.record.value.clone()).unwrap().timestamp
-                );
+                let all_same: bool = recvec.windows(2).all(|w| w[0].2 == w[1].2);
+                let max_record = recvec.into_iter().max_by_key(|(_, _, entry)| entry.timestamp);
                 if !all_same {} // TODO send message to audit key providers
                 match max_record {
-                    Some(PeerRecord{record, peer}) => {
-                    let dht_entry = DhtEntry::from_bytes(record.value.clone()).unwrap();
+                    Some((PeerRecord{record, peer}, key, dht_entry)) => {
+                    // a record served from the local store has no peer, fall back to its publisher
+                    let local_peer_id = *self.swarm.local_peer_id();
+                    let peer = peer.or(record.publisher).filter(|p| p != &local_peer_id);
                     let dht_hash = dht_entry.current.clone();
                     let mut dht_time = dht_entry.timestamp;
-                    let key = record.key.to_vec();
+                    // roots this device doesn't sync are skipped
+                    match self.roots.from_key(&key) {
+                        Some(fp) if !self.ignored(&fp) => {},
+                        _ => return
+                    }
                     let local_retrieve = self.db.get(key.clone());
-                    let local_fp = FilePath::new_from_key(key.to_vec());
-                    let current_hash = match path_to_hash(local_fp.clone()) {
+                    // deletions are reconciled separately from live versions
+                    let local_deleted = local_retrieve.clone()
+                        .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()))
+                        .map_or(false, |entry| entry.deleted);
+                    if dht_entry.deleted || local_deleted {
+                        self.reconcile_tombstone(key, dht_entry, peer);
+                        return
+                    }
+                    let local_fp = match self.roots.from_key(&key) {
+                        Some(fp) => fp,
+                        None => return
+                    };
+                    let current_hash = match self.db.hash_file(&local_fp) {
                         Some(hash) => hash,
                         None => {
                             "no_file".into()
@@ -227,13 +621,16 @@ impl NetworkEvent {
                                 let new_entry = DhtEntry {
                                     current: current_hash.clone(),
                                     previous: Some(local_hash.clone()),
-                                    timestamp: local_time
+                                    timestamp: local_time,
+                                    deleted: false,
+                                    origin: dht_entry.origin.clone()
                                 };
                                 local_hash = current_hash.clone();
                                 self.db.insert(key.clone(), new_entry.clone().to_bytes());
                                 add_to_dht(
                                     &mut self.swarm.behaviour_mut().kademlia,
-                                    local_fp.sub_home(),
+                                    &self.group,
+                                    local_fp.to_key(),
                                     new_entry);
                                 println!("Updated local and dht as local db not up to date");
                             }
@@ -245,59 +642,83 @@ impl NetworkEvent {
                                 let new_entry = DhtEntry {
                                     current: current_hash.clone(),
                                     previous: dht_entry.previous,
-                                    timestamp: max_time
+                                    timestamp: max_time,
+                                    deleted: false,
+                                    origin: dht_entry.origin
                                 };
                                 self.db.insert(key.clone(), new_entry.clone().to_bytes());
                                 add_to_dht(
                                     &mut self.swarm.behaviour_mut().kademlia,
-                                    local_fp.sub_home(),
+                                    &self.group,
+                                    local_fp.to_key(),
                                     new_entry);
                                 println!("Updated local and dht with differing timestamps and equal hashes");
                                 }
+                            } else if &current_hash == "no_file" {
+                                if let Some(peer) = peer {
+                                    self.request_file(&peer, &key, dht_entry.clone());
+                                    println!("Requesting file as no local file but dht and local db entries");
+                                }
                             } else {
                                 // current hash is different to dht
-                                // compare timestamps and if dht > local
-                                if dht_time > local_time {
-                                    // trigger file transfer
-                                    // update entry in local is handled on response success
-                                    self.request_file(&peer.unwrap(), &key, dht_entry.clone());
-                                    println!("Reqeusting file as hashes different and DHT more recent");
-
-                                } else if local_time > dht_time {
-                                    // push local time to dht and send message for file tf
+                                // version ancestry decides the winner rather than timestamps
+                                self.db.record_edit(&key, &dht_entry);
+                                if &current_hash != &local_hash {
+                                    // edit on disk not yet seen by the watcher
+                                    self.db.record_ancestry(&current_hash, &Some(local_hash.clone()));
+                                }
+                                if self.db.is_ancestor(&dht_hash, &current_hash) {
+                                    // local descends from the DHT version, push local
                                     let new_entry = DhtEntry {
                                         current: current_hash.clone(),
-                                        previous: Some(dht_entry.clone().current),
-                                        timestamp: local_time
+                                        previous: Some(local_hash.clone()).filter(|hash| hash != &current_hash)
+                                            .or(local_entry.previous),
+                                        timestamp: std::cmp::max(local_time, dht_time),
+                                        deleted: false,
+                                        origin: if current_hash != local_hash {
+                                            self.swarm.local_peer_id().to_bytes()
+                                        } else {
+                                            local_entry.origin
+                                        }
                                     };
+                                    self.db.insert(key.clone(), new_entry.to_bytes());
                                     add_to_dht(
                                         &mut self.swarm.behaviour_mut().kademlia,
-                                        local_fp.sub_home(),
+                                        &self.group,
+                                        local_fp.to_key(),
                                         new_entry);
-                                        println!("Updated local and dht with differing timestamps and equal hashes");
-
-                                } else {
-                                    // TODO decide how to handle equal timestamps but differing
-                                    // hashes. For now...
-                                    // when timestamps are equal do nothing
-                                    // this case shouldn't really occur...
-                                    // for testing panic
-                                    panic!("Differing hashes (dht vs local) but same timestamp")
-                                }
-                                if &current_hash == "no_file" {
-                                    self.request_file(&peer.unwrap(), &key, dht_entry.clone());
-                                    println!("Requesting file as no local file but dht and local db entries");
+                                    println!("Updated dht as local version descends from dht version");
+                                } else if let Some(peer) = peer {
+                                    if self.db.is_ancestor(&current_hash, &dht_hash) {
+                                        // fast-forward to the DHT version
+                                        // update entry in local is handled on response success
+                                        self.request_file(&peer, &key, dht_entry.clone());
+                                        println!("Requesting file as dht version descends from local");
+                                    } else {
+                                        // ancestry not known locally, the response carries the
+                                        // peer's chain and decides between fast-forward and fork
+                                        self.request_reconcile(&peer, &key, dht_entry.clone());
+                                    }
                                 }
                             }
                         },
                         None => {
-                            // TODO handle no local db entry for key
-                            // this is in the setting of a DHT entry
-                            // check if local file present
-                            // if not then retrieve it and add DHT entry
-                            // to local, If there is a local file then overwrite
-                            // with DHT information and fetch file from peer
-
+                            // no local db entry for key but there is a DHT entry
+                            // if the local file already matches then record the DHT entry
+                            // otherwise fetch the file from the peer, the local entry
+                            // is written when the response arrives
+                            if &current_hash == &dht_hash {
+                                self.db.insert(key.clone(), dht_entry.to_bytes());
+                                println!("Recorded dht entry locally as file matches");
+                            } else if let Some(peer) = peer {
+                                if &current_hash == "no_file" {
+                                    self.request_file(&peer, &key, dht_entry.clone());
+                                    println!("Requesting file as no local db entry");
+                                } else {
+                                    // untracked local file differs from the DHT version
+                                    self.request_conflict(&peer, &key, dht_entry.clone());
+                                }
+                            }
                         }
                     }
                     }
@@ -309,60 +730,73 @@ impl NetworkEvent {
                     }
                 };
             }
-            Err(libp2p::kad::GetRecordError::NotFound {key, closest_peers}) => {
+            Err(libp2p::kad::GetRecordError::NotFound {key: record_key, closest_peers}) => {
                 println!("failed due to not found");
+                let key = match self.dht_keys.get(&record_key.to_vec()) {
+                    Some(key) => key.clone(),
+                    None => return
+                };
                 // check local file present
-                let local_fp = FilePath::new_from_key(key.to_vec());
-                match path_to_hash(local_fp.clone()) {
+                let local_fp = match self.roots.from_key(&key.to_vec()) {
+                    Some(fp) => fp,
+                    None => return
+                };
+                match self.db.hash_file(&local_fp) {
                     Some(current_hash) => {
                         match self.db.get(key.to_vec().clone()) {
                             Some(entry) => {
                                 let local_entry = DhtEntry::from_bytes(entry.to_vec()).unwrap();
+                                // a file back on disk after a local deletion is a new version
+                                let timestamp = if local_entry.deleted {
+                                    Utc::now().timestamp()
+                                } else {
+                                    local_entry.timestamp
+                                };
                                 let new_entry = DhtEntry {
                                     current: current_hash.clone(),
                                     previous: local_entry.previous,
-                                    timestamp: local_entry.timestamp
+                                    timestamp,
+                                    deleted: false,
+                                    origin: if current_hash == local_entry.current {
+                                        local_entry.origin
+                                    } else {
+                                        self.swarm.local_peer_id().to_bytes()
+                                    }
                                 };
                                 let local_hash = current_hash.clone();
                                 self.db.insert(key.to_vec(), new_entry.clone().to_bytes());
                                 add_to_dht(
                                     &mut self.swarm.behaviour_mut().kademlia,
-                                    local_fp.sub_home(),
+                                    &self.group,
+                                    local_fp.to_key(),
                                     new_entry);
                                 println!("Added to dht and local from local entry but no dht entry");
-                                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(
-                                    self.topic.clone(),
-                                    nm::to_bytes(nm::Messages::FileCheck {
-                                        filepath: local_fp.struct_to_bytes(),
-                                        timestamp: chrono::Utc::now().timestamp()
-                                    })
-                                ) {
-                                    println!("Publish error: {:?}", e);
-                                };
+                                self.publish(nm::Messages::FileCheck {
+                                    filepath: local_fp.to_bytes(),
+                                    timestamp: chrono::Utc::now().timestamp()
+                                });
                             },
                             None => {
                                 // no local db entry and file on disk
                                 let new_entry = DhtEntry {
                                     current: current_hash.clone(),
                                     previous: None,
-                                    timestamp: Utc::now().timestamp()
+                                    timestamp: Utc::now().timestamp(),
+                                    deleted: false,
+                                    origin: self.swarm.local_peer_id().to_bytes()
                                 };
                                 let local_hash = current_hash.clone();
                                 self.db.insert(key.to_vec(), new_entry.clone().to_bytes());
                                 add_to_dht(
                                     &mut self.swarm.behaviour_mut().kademlia,
-                                    local_fp.sub_home(),
+                                    &self.group,
+                                    local_fp.to_key(),
                                     new_entry);
                                 println!("Added to dht and local from no entry in dht or local");
-                                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(
-                                    self.topic.clone(),
-                                    nm::to_bytes(nm::Messages::FileCheck {
-                                        filepath: local_fp.struct_to_bytes(),
-                                        timestamp: chrono::Utc::now().timestamp()
-                                    })
-                                ) {
-                                    println!("Publish error: {:?}", e);
-                                };
+                                self.publish(nm::Messages::FileCheck {
+                                    filepath: local_fp.to_bytes(),
+                                    timestamp: chrono::Utc::now().timestamp()
+                                });
                             }
                         }
                     },
@@ -381,17 +815,14 @@ impl NetworkEvent {
     }
 
     pub fn get_files_from_dirs(&mut self, dirs: Vec<PathBuf>) -> Vec<FilePath> {
-        let home = std::env::var("HOME").unwrap();
         let mut paths: Vec<_> = vec![];
+        let rules = self.rules.clone();
         for path in dirs.into_iter() {
-            for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+            let walk = WalkDir::new(path).into_iter()
+                .filter_entry(|e| !rules.read().unwrap().is_ignored(e.path()));
+            for entry in walk.filter_map(|e| e.ok()) {
                 if !entry.path().is_dir() {
-                    paths.push(
-                        FilePath {
-                            home: home.clone(),
-                            full: entry.into_path().into_os_string().into_string().unwrap()
-                        }
-                    );
+                    paths.extend(self.roots.from_path(entry.into_path()));
                 }
                 // println!("{}", entry.path().display());
             }
@@ -399,31 +830,1361 @@ impl NetworkEvent {
         paths
     }
 
-    pub fn sync(&mut self) {
-        //TODO check local and compare DHT. Any different files download from peer and
-        // update local. Perform this for each new peer.
-
-    }
-
     async fn handle_watch(&mut self, command: Command) {
         match command {
-            // TODO write comand hooks
-            Command::EditFileAdd{path: path} => {
-                let path_string = path.to_str().unwrap().to_string();
+            Command::EditFileAdd{path} => {
+                self.local_change(path, true);
+            },
+            Command::EditFileChange{path} => {
+                self.local_change(path, false);
+            },
+            Command::EditFileDelete{path} => {
+                self.local_delete(path);
+            },
+            Command::EditFileMove{from, to} => {
+                self.local_move(from, to);
             },
             _ => {println!("unhandled")}
         }
     }
 
-    async fn handle_cli(&mut self, command: CliCommand) {
-        match command {
-            // TODO write comand hooks
-            CliCommand {Push: path} => {
-                println!("CLI command to push");
+    // ignore rules are applied to remote keys too, a file one device skips is skipped everywhere
+    fn ignored(&self, fp: &FilePath) -> bool {
+        self.rules.read().unwrap().is_ignored(fp.to_path())
+    }
+
+    // only paired devices hold the group key to read it
+    fn publish(&mut self, msg: nm::Messages) {
+        let sealed = self.group.seal(&nm::to_bytes(msg));
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), sealed) {
+            println!("Publish error: {:?}", e);
+        };
+    }
+
+    // pairing messages go out before the other device has the group key
+    fn publish_plain(&mut self, msg: nm::Messages) {
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), nm::to_bytes(msg)) {
+            println!("Publish error: {:?}", e);
+        };
+    }
+
+    // record keys are hashes, shown as the file key when we asked for it
+    fn record_name(&self, record_key: &Key) -> String {
+        match self.dht_keys.get(&record_key.to_vec()) {
+            Some(key) => String::from_utf8_lossy(key).to_string(),
+            None => record_key.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+        }
+    }
+
+    fn dht_get(&mut self, key: &[u8]) {
+        let record_key = record_key(&self.group, key);
+        self.dht_keys.insert(record_key.to_vec(), key.to_vec());
+        self.swarm.behaviour_mut().kademlia.get_record(record_key, Quorum::One);
+    }
+
+    /// LOCAL FILE EVENTS
+    // record a new version in the local db and DHT then tell peers
+    fn local_change(&mut self, path: PathBuf, added: bool) {
+        let fp = match self.roots.from_path(path) {
+            Some(fp) => fp,
+            None => return
+        };
+        if self.ignored(&fp) {
+            return
+        }
+        let key = fp.to_bytes();
+        if added && !self.recent_removed.is_empty() {
+            // a new file with the content of a file just removed was moved there
+            let untracked = self.db.get(key.clone())
+                .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()))
+                .map_or(true, |entry| entry.deleted);
+            let from = std::fs::read(fp.to_path()).ok()
+                .and_then(|data| self.recent_removed.get(&sha256::digest(&data[..])).cloned());
+            if let (true, Some(from)) = (untracked, from) {
+                self.local_move(from, fp.to_path().to_path_buf());
+                return
+            }
+        }
+        let current_hash = match self.db.hash_file(&fp) {
+            Some(hash) => hash,
+            None => return // file already gone again
+        };
+        let local_entry = self.db.get(key.clone())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()));
+        // files written by a transfer and repeat events for the same content are not new edits
+        if let Some(entry) = &local_entry {
+            if entry.current == current_hash && !entry.deleted {
+                return
+            }
+        }
+        let new_entry = DhtEntry {
+            current: current_hash,
+            previous: local_entry.as_ref().map(|entry| entry.current.clone()),
+            timestamp: Utc::now().timestamp(),
+            deleted: false,
+            origin: self.swarm.local_peer_id().to_bytes()
+        };
+        self.db.insert(key.clone(), new_entry.to_bytes());
+        self.db.record_edit(&key, &new_entry);
+        if let Ok(data) = std::fs::read(fp.to_path()) {
+            self.db.record_version(&key, &new_entry, &data);
+        }
+        self.key_2_filepath.insert(key.clone(), fp.clone());
+        // edits to a file with merge conflict markers are published on resolve
+        if self.db.get_conflict(key.clone()).map_or(false, |conflict| conflict.markers) {
+            println!("Local file changed {:?}, unresolved merge conflict so not published", fp.to_key());
+            return
+        }
+        add_to_dht(
+            &mut self.swarm.behaviour_mut().kademlia,
+            &self.group,
+            fp.to_key(),
+            new_entry.clone());
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        if added && local_entry.map_or(true, |entry| entry.deleted) {
+            println!("Local file added {:?}", fp.to_key());
+            self.publish(nm::Messages::Added { path: fp.to_key(), peerid });
+        } else {
+            println!("Local file changed {:?}", fp.to_key());
+            self.publish_changed(key, &new_entry);
+        }
+    }
+
+    // put the local version back in the DHT and tell peers, one announced an older one
+    fn announce_local(&mut self, key: Vec<u8>) {
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return
+        };
+        let entry = self.db.get(key.clone())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()))
+            .filter(|entry| !entry.deleted);
+        match (entry, self.db.hash_file(&fp)) {
+            (Some(entry), Some(hash)) if entry.current == hash => {
+                if self.db.get_conflict(key.clone()).map_or(false, |conflict| conflict.markers) {
+                    return
+                }
+                add_to_dht(
+                    &mut self.swarm.behaviour_mut().kademlia,
+                    &self.group,
+                    fp.to_key(),
+                    entry.clone());
+                self.publish_changed(key, &entry);
+            },
+            // an edit the watcher hasn't reported yet is recorded and published with it
+            _ => self.local_change(fp.to_path().to_path_buf(), false)
+        }
+    }
+
+    // gossip a change with a patch against the previous version when it is worth it
+    fn publish_changed(&mut self, key: Vec<u8>, entry: &DhtEntry) {
+        let previous = entry.previous.clone().unwrap_or_default();
+        let base = self.db.get_content(&previous)
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let new = self.db.get_content(&entry.current)
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let patch = match (base, new) {
+            (Some(base), Some(new)) => {
+                let patch = patch::create(&base, &new);
+                // receivers fetch the whole file when the patch saves nothing
+                if patch.len() < MAX_PATCH_BYTES && patch.len() < new.len() / 2 {
+                    patch
+                } else {
+                    "".into()
+                }
+            },
+            _ => "".into()
+        };
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        self.publish(nm::Messages::Changed {
+            path: String::from_utf8(key).unwrap(),
+            patch,
+            previous,
+            current: entry.current.clone(),
+            timestamp: entry.timestamp,
+            peerid,
+            origin: entry.origin.clone()
+        });
+    }
+
+    // a peer changed a file, patch it in place when the base matches, otherwise fetch it
+    fn remote_change(&mut self, path: String, patch: String, entry: DhtEntry, peer: Option<PeerId>) {
+        let key = path.as_bytes().to_vec();
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return
+        };
+        if self.ignored(&fp) {
+            return
+        }
+        self.db.record_edit(&key, &entry);
+        let current_hash = self.db.hash_file(&fp);
+        if current_hash.as_ref() == Some(&entry.current) {
+            return
+        }
+        if let Some(current_hash) = &current_hash {
+            if descent(&mut self.db, current_hash, &entry.current) == Descent::Stale {
+                println!("Keeping local {:?}, announced version is older", fp.to_key());
+                self.announce_local(key);
+                return
+            }
+        }
+        if current_hash.is_some() && current_hash == entry.previous && !patch.is_empty() {
+            let patched = std::fs::read(fp.to_path()).ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .and_then(|base| patch::apply(&base, &patch));
+            if let Some(patched) = patched {
+                let data = patched.into_bytes();
+                if bytes_to_hash(&key, &data) == entry.current {
+                    match self.record_then_write(&key, &fp, &entry, &data) {
+                        Ok(_) => println!("Applied patch to {:?}", fp.to_key()),
+                        Err(e) => println!("Unable to write patched file {:?}: {:?}", fp.to_key(), e)
+                    }
+                    return
+                }
+            }
+            println!("Patch for {:?} did not apply, requesting file", fp.to_key());
+        }
+        if let Some(peer) = peer {
+            match current_hash {
+                Some(current_hash) if current_hash != entry.previous.clone().unwrap_or_default()
+                    && !self.db.is_ancestor(&current_hash, &entry.current) => {
+                    self.request_reconcile(&peer, &key, entry);
+                },
+                _ => self.request_file(&peer, &key, entry)
+            }
+        }
+    }
+
+    // hold a delete briefly, the file may show up again under another name
+    fn local_delete(&mut self, path: PathBuf) {
+        let fp = match self.roots.from_path(path) {
+            Some(fp) => fp,
+            None => return
+        };
+        if self.ignored(&fp) {
+            return
+        }
+        // a directory moved out of the root takes the files under it with it
+        let prefix = format!("{}/", fp.to_key());
+        let inside: Vec<FilePath> = self.db.entries().into_iter()
+            .filter(|(key, entry)| !entry.deleted && key.starts_with(prefix.as_bytes()))
+            .filter_map(|(key, _)| self.roots.from_key(&key))
+            .collect();
+        if !inside.is_empty() {
+            for fp in inside {
+                self.remove_tracked(fp);
+            }
+            return
+        }
+        let digest = self.db.get(fp.to_bytes())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()))
+            .filter(|entry| !entry.deleted)
+            .and_then(|entry| self.db.get_content(&entry.current))
+            .map(|data| sha256::digest(&data[..]));
+        match digest {
+            Some(digest) => {
+                let path = fp.to_path().to_path_buf();
+                self.recent_removed.insert(digest, path.clone());
+                let mut sender = self.commandsender.clone();
+                async_std::task::spawn(async move {
+                    async_std::task::sleep(MOVE_WINDOW).await;
+                    _ = sender.send(Command::SettleDelete { path }).await;
+                });
+            },
+            None => self.remove_tracked(fp)
+        }
+    }
+
+    // the held delete was not claimed by a move
+    fn settle_delete(&mut self, path: PathBuf) {
+        let held = self.recent_removed.len();
+        self.recent_removed.retain(|_, removed| removed != &path);
+        if self.recent_removed.len() < held {
+            if let Some(fp) = self.roots.from_path(path) {
+                self.remove_tracked(fp);
+            }
+        }
+    }
+
+    // forget a deleted file locally and in the DHT then tell peers
+    fn remove_tracked(&mut self, fp: FilePath) {
+        // editors that save by replacing the file fire a delete before the new file lands
+        if fp.to_path().exists() {
+            return
+        }
+        let key = fp.to_bytes();
+        let local_entry = match self.db.get(key.clone())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec())) {
+            Some(entry) if !entry.deleted => entry,
+            // untracked, or already removed on a message from a peer
+            _ => return
+        };
+        // tombstone keeps the last known hash so peers can tell what was deleted
+        let tombstone = DhtEntry {
+            current: local_entry.current,
+            previous: local_entry.previous,
+            timestamp: Utc::now().timestamp(),
+            deleted: true,
+            origin: self.swarm.local_peer_id().to_bytes()
+        };
+        self.db.insert(key.clone(), tombstone.to_bytes());
+        add_to_dht(
+            &mut self.swarm.behaviour_mut().kademlia,
+            &self.group,
+            fp.to_key(),
+            tombstone.clone());
+        self.key_2_filepath.remove(&key);
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        self.db.add_ack(key, tombstone.timestamp, peerid.clone());
+        println!("Local file removed {:?}", fp.to_key());
+        self.publish(nm::Messages::Removed {
+            path: fp.to_key(),
+            hash: tombstone.current,
+            timestamp: tombstone.timestamp,
+            peerid
+        });
+    }
+
+    /// MOVES
+    // a rename keeps the version chain, the new key descends from the old one
+    fn local_move(&mut self, from: PathBuf, to: PathBuf) {
+        if to.is_dir() {
+            // a directory move renames every file below it
+            for entry in WalkDir::new(&to).into_iter().filter_map(|e| e.ok()) {
+                if !entry.path().is_dir() {
+                    let rel = entry.path().strip_prefix(&to).unwrap();
+                    self.local_move(from.join(rel), entry.path().to_path_buf());
+                }
+            }
+            return
+        }
+        self.recent_removed.retain(|_, removed| removed != &from);
+        let (from_fp, to_fp) = match (self.roots.from_path(from), self.roots.from_path(to.clone())) {
+            (Some(from_fp), Some(to_fp)) => (from_fp, to_fp),
+            // moved into a root from outside
+            (None, Some(_)) => return self.local_change(to, true),
+            // moved out of every root
+            (Some(from_fp), None) => return self.remove_tracked(from_fp),
+            (None, None) => return
+        };
+        let from_key = from_fp.to_bytes();
+        let to_key = to_fp.to_bytes();
+        let from_entry = self.db.get(from_key.clone())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()))
+            .filter(|entry| !entry.deleted);
+        let to_tracked = self.db.get(to_key.clone())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()))
+            .map_or(false, |entry| !entry.deleted);
+        let from_entry = match from_entry {
+            Some(entry) if !to_tracked && !self.ignored(&from_fp) && !self.ignored(&to_fp) => entry,
+            from_entry => {
+                // saved over from a temp file, or renamed over a tracked file: an edit of the destination
+                if from_entry.is_some() {
+                    self.remove_tracked(from_fp);
+                }
+                self.local_change(to, !to_tracked);
+                return
+            }
+        };
+        let data = match std::fs::read(&to) {
+            Ok(data) => data,
+            Err(_) => return // moved again already
+        };
+        let timestamp = Utc::now().timestamp();
+        let tombstone = DhtEntry {
+            current: from_entry.current.clone(),
+            previous: from_entry.previous,
+            timestamp,
+            deleted: true,
+            origin: self.swarm.local_peer_id().to_bytes()
+        };
+        let entry = DhtEntry {
+            current: bytes_to_hash(&to_key, &data),
+            previous: Some(from_entry.current),
+            timestamp,
+            deleted: false,
+            origin: self.swarm.local_peer_id().to_bytes()
+        };
+        self.db.insert(from_key.clone(), tombstone.to_bytes());
+        self.db.insert(to_key.clone(), entry.to_bytes());
+        self.db.record_ancestry(&entry.current, &entry.previous);
+        self.db.record_version(&to_key, &entry, &data);
+        add_to_dht(
+            &mut self.swarm.behaviour_mut().kademlia,
+            &self.group,
+            from_fp.to_key(),
+            tombstone.clone());
+        add_to_dht(
+            &mut self.swarm.behaviour_mut().kademlia,
+            &self.group,
+            to_fp.to_key(),
+            entry.clone());
+        self.key_2_filepath.remove(&from_key);
+        self.key_2_filepath.insert(to_key, to_fp.clone());
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        self.db.add_ack(from_key, timestamp, peerid.clone());
+        println!("Local file moved {:?} to {:?}", from_fp.to_key(), to_fp.to_key());
+        self.publish(nm::Messages::Moved {
+            from: from_fp.to_key(),
+            to: to_fp.to_key(),
+            previous: tombstone.current,
+            current: entry.current,
+            timestamp,
+            peerid
+        });
+    }
+
+    // a peer moved a file, rename it here when we hold the same content
+    fn remote_move(&mut self, from: String, to: String, entry: DhtEntry, peer: Option<PeerId>) {
+        let from_key = from.as_bytes().to_vec();
+        let to_key = to.as_bytes().to_vec();
+        let (from_fp, to_fp) = match (self.roots.from_key(&from_key), self.roots.from_key(&to_key)) {
+            (Some(from_fp), Some(to_fp)) => (from_fp, to_fp),
+            _ => {
+                // one side is in a root this device doesn't sync
+                let tombstone = DhtEntry {
+                    current: entry.previous.clone().unwrap_or_default(),
+                    previous: None,
+                    timestamp: entry.timestamp,
+                    deleted: true,
+                    origin: entry.origin.clone()
+                };
+                self.reconcile_tombstone(from_key, tombstone, peer);
+                self.remote_change(to, "".into(), entry, peer);
+                return
+            }
+        };
+        let previous = entry.previous.clone().unwrap_or_default();
+        let tombstone = DhtEntry {
+            current: previous.clone(),
+            previous: None,
+            timestamp: entry.timestamp,
+            deleted: true,
+            origin: entry.origin.clone()
+        };
+        let data = std::fs::read(from_fp.to_path()).ok();
+        let movable = !self.ignored(&from_fp) && !self.ignored(&to_fp)
+            && !to_fp.to_path().exists()
+            && data.as_ref().map_or(false, |data| {
+                bytes_to_hash(&from_key, data) == previous && bytes_to_hash(&to_key, data) == entry.current
+            });
+        if !movable {
+            // fall back to a delete of the source and a fetch of the destination
+            self.reconcile_tombstone(from_key, tombstone, peer);
+            self.remote_change(to, "".into(), entry, peer);
+            return
+        }
+        // entries are recorded before renaming so the watcher sees no new edit
+        self.db.insert(from_key.clone(), tombstone.to_bytes());
+        self.db.insert(to_key.clone(), entry.to_bytes());
+        self.db.record_ancestry(&entry.current, &entry.previous);
+        self.db.record_version(&to_key, &entry, &data.unwrap());
+        if let Some(parent) = to_fp.to_path().parent() {
+            _ = std::fs::create_dir_all(parent);
+        }
+        if let Err(e) = std::fs::rename(from_fp.to_path(), to_fp.to_path()) {
+            println!("Unable to move {:?} to {:?}: {:?}", from_fp.to_key(), to_fp.to_key(), e);
+            return
+        }
+        println!("Moved {:?} to {:?} as moved by peer", from_fp.to_key(), to_fp.to_key());
+        self.key_2_filepath.remove(&from_key);
+        self.key_2_filepath.insert(to_key, to_fp);
+        self.ack_tombstone(from_key, entry.timestamp);
+    }
+
+    /// CONFLICTS
+    fn request_reconcile(&mut self, peer: &PeerId, key: &Vec<u8>, dht_entry: DhtEntry) {
+        // a fork already recorded is not fetched again until resolved
+        if let Some(conflict) = self.db.get_conflict(key.clone()) {
+            if conflict.remote == dht_entry.current {
+                return
+            }
+        }
+        self.request_transfer(peer, key, dht_entry, Transfer::Reconcile);
+    }
+
+    fn request_conflict(&mut self, peer: &PeerId, key: &Vec<u8>, dht_entry: DhtEntry) {
+        // the same conflict is seen again on each reconciliation until resolved
+        if let Some(conflict) = self.db.get_conflict(key.clone()) {
+            if conflict.remote == dht_entry.current {
+                return
+            }
+        }
+        println!("Conflict for {:?}, fetching remote version as a copy", String::from_utf8(key.clone()));
+        self.request_transfer(peer, key, dht_entry, Transfer::Conflict);
+    }
+
+    // the local file is left in place and the remote version written beside it
+    fn write_conflict_copy(&mut self, key: Vec<u8>, peer: PeerId, response: ResponseData, markers: bool) {
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return
+        };
+        let local_hash = match self.db.hash_file(&fp) {
+            Some(hash) => hash,
+            None => "no_file".into()
+        };
+        let device = peer.to_base58();
+        let copy = fp.conflict_copy(&device[device.len() - 8..]);
+        if let Err(e) = std::fs::write(copy.to_path(), &response.data) {
+            println!("Unable to write conflict copy {:?}: {:?}", copy.to_key(), e);
+            return
+        }
+        self.db.add_conflict(Conflict {
+            path: fp.to_key(),
+            copy: copy.full.clone(),
+            local: local_hash,
+            remote: response.metadata.current,
+            device: device,
+            timestamp: Utc::now().timestamp(),
+            markers
+        });
+        println!("Conflict for {:?}, remote version written to {:?}", fp.to_key(), copy.to_key());
+    }
+
+    // three-way merge of a forked org file against the common ancestor
+    // returns false when the fork can't be merged and needs a conflict copy
+    fn merge_fork(&mut self, key: Vec<u8>, peer: PeerId, local_hash: &String, response: &ResponseData) -> bool {
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return false
+        };
+        if fp.to_path().extension().map_or(true, |ext| ext != "org") {
+            return false
+        }
+        let local_ancestors = self.db.ancestors(local_hash);
+        let base = match self.db.ancestors(&response.metadata.current).into_iter()
+            .find(|hash| local_ancestors.contains(hash)) {
+            Some(base) => base,
+            None => {
+                println!("No common ancestor for {:?}", fp.to_key());
+                return false
+            }
+        };
+        let ancestor = self.db.get_content(&base)
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let local = std::fs::read(fp.to_path()).ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let remote = String::from_utf8(response.data.clone()).ok();
+        let (ancestor, local, remote) = match (ancestor, local, remote) {
+            (Some(ancestor), Some(local), Some(remote)) => (ancestor, local, remote),
+            _ => {
+                println!("Ancestor content unavailable for {:?}", fp.to_key());
+                return false
+            }
+        };
+        let device = peer.to_base58();
+        match merge::merge(&ancestor, &local, &remote, &device[device.len() - 8..]) {
+            Ok(merged) if merged == remote => {
+                // remote already holds the local edits, e.g. it merged them first
+                println!("Merge of {:?} matches remote version, fast-forwarding", fp.to_key());
+                self.write_received(key, response.clone());
+            },
+            Ok(merged) => {
+                let new_entry = self.write_merged(key.clone(), local_hash, &response.metadata.current, merged);
+                add_to_dht(
+                    &mut self.swarm.behaviour_mut().kademlia,
+                    &self.group,
+                    fp.to_key(),
+                    new_entry.clone());
+                self.publish_changed(key, &new_entry);
+                println!("Merged {:?} with remote version", fp.to_key());
+            },
+            Err(marked) => {
+                // kept locally until resolved so the markers don't spread to peers
+                self.write_merged(key.clone(), local_hash, &response.metadata.current, marked);
+                self.write_conflict_copy(key, peer, response.clone(), true);
+                println!("Merge of {:?} has overlapping changes, conflict markers written", fp.to_key());
+            }
+        }
+        true
+    }
+
+    fn write_merged(&mut self, key: Vec<u8>, local_hash: &String, remote_hash: &String, merged: String) -> DhtEntry {
+        let fp = self.roots.from_key(&key).expect("Merged file to have a local root");
+        let data = merged.into_bytes();
+        let new_entry = DhtEntry {
+            current: bytes_to_hash(&key, &data),
+            previous: Some(local_hash.clone()),
+            timestamp: Utc::now().timestamp(),
+            deleted: false,
+            origin: self.swarm.local_peer_id().to_bytes()
+        };
+        self.db.record_edit(&key, &new_entry);
+        // both sides count as parents so the remote fast-forwards to the merge
+        self.db.record_merge_parent(&new_entry.current, remote_hash);
+        if let Err(e) = self.record_then_write(&key, &fp, &new_entry, &data) {
+            println!("Unable to write merged file {:?}: {:?}", fp.to_key(), e);
+        }
+        new_entry
+    }
+
+    // entry is recorded before writing so the watcher sees no new edit
+    fn record_then_write(&mut self, key: &Vec<u8>, fp: &FilePath, entry: &DhtEntry, data: &[u8]) -> std::io::Result<()> {
+        self.db.insert(key.clone(), entry.to_bytes());
+        self.db.record_version(key, entry, data);
+        self.expect_write(&fp.full, data);
+        std::fs::write(fp.to_path(), data)
+    }
+
+    fn list_conflicts(&mut self) -> CliReply {
+        let conflicts = self.db.conflicts().into_iter()
+            .map(|conflict| ConflictInfo {
+                path: conflict.path,
+                copy: conflict.copy,
+                device: conflict.device,
+                markers: conflict.markers
+            })
+            .collect();
+        Ok(CliResponse::Conflicts { conflicts })
+    }
+
+    // keep either the local version or the remote copy as a new version
+    fn resolve_conflict(&mut self, path: String, keep: String) -> CliReply {
+        let key = self.cli_key(&path)?;
+        let path = String::from_utf8(key.clone()).unwrap();
+        let conflict = self.db.get_conflict(key.clone()).ok_or_else(|| CliError::new(
+            CliErrorKind::NotFound, format!("No conflict recorded for {:?}", path)))?;
+        let fp = self.roots.from_key(&key).expect("Control path to be under a root");
+        match keep.as_str() {
+            "local" => {
+                let current_hash = self.db.hash_file(&fp).ok_or_else(|| CliError::new(
+                    CliErrorKind::NotFound, format!("Local file {:?} missing, keep remote instead", path)))?;
+                // a newer entry descending from the remote version wins everywhere
+                let new_entry = DhtEntry {
+                    current: current_hash,
+                    previous: Some(conflict.remote.clone()),
+                    timestamp: Utc::now().timestamp(),
+                    deleted: false,
+                    origin: self.swarm.local_peer_id().to_bytes()
+                };
+                self.db.insert(key.clone(), new_entry.to_bytes());
+                self.db.record_edit(&key, &new_entry);
+                add_to_dht(
+                    &mut self.swarm.behaviour_mut().kademlia,
+                    &self.group,
+                    fp.to_key(),
+                    new_entry.clone());
+                self.publish_changed(key.clone(), &new_entry);
+            },
+            "remote" => {
+                let data = std::fs::read(&conflict.copy).map_err(|e| CliError::new(
+                    CliErrorKind::Io, format!("Unable to read conflict copy {:?}: {}", conflict.copy, e)))?;
+                std::fs::write(fp.to_path(), &data).map_err(|e| CliError::new(
+                    CliErrorKind::Io, format!("Unable to write {:?}: {}", path, e)))?;
+                // recorded now rather than waiting on the watcher
+                self.db.remove_conflict(key.clone());
+                self.local_change(fp.to_path().to_path_buf(), false);
+            },
+            _ => {
+                return Err(CliError::new(
+                    CliErrorKind::BadRequest, format!("Expected local or remote to keep, got {:?}", keep)))
+            }
+        }
+        if let Err(e) = std::fs::remove_file(&conflict.copy) {
+            println!("Unable to remove conflict copy {:?}: {:?}", conflict.copy, e);
+        }
+        self.db.remove_conflict(key);
+        Ok(CliResponse::Done { message: format!("Resolved conflict for {:?} keeping {}", path, keep) })
+    }
+
+    /// HISTORY
+    fn list_history(&mut self, path: String) -> CliReply {
+        let key = self.cli_key(&path)?;
+        let path = String::from_utf8(key.clone()).unwrap();
+        let versions: Vec<VersionInfo> = self.db.versions(&key).into_iter()
+            .map(|version| VersionInfo {
+                hash: version.hash,
+                previous: version.previous,
+                timestamp: version.timestamp,
+                device: version.device
+            })
+            .collect();
+        if versions.is_empty() {
+            return Err(CliError::new(CliErrorKind::NotFound, format!("No history for {:?}", path)))
+        }
+        Ok(CliResponse::History { path, versions })
+    }
+
+    fn show_version(&mut self, path: String, hash: String) -> CliReply {
+        let key = self.cli_key(&path)?;
+        let path = String::from_utf8(key.clone()).unwrap();
+        match self.db.find_version(&key, &hash) {
+            Some(version) => match self.db.get_content(&version.hash) {
+                Some(data) => Ok(CliResponse::Content {
+                    path,
+                    hash: version.hash,
+                    content: String::from_utf8_lossy(&data).to_string()
+                }),
+                None => Err(CliError::new(
+                    CliErrorKind::NotFound, format!("Content of {:?} version {} not stored", path, version.hash)))
+            },
+            None => Err(CliError::new(
+                CliErrorKind::NotFound, format!("No single version of {:?} matching {:?}", path, hash)))
+        }
+    }
+
+    // writing the old content back is a new local edit, so it propagates to peers
+    fn restore_version(&mut self, path: String, hash: String) -> CliReply {
+        let key = self.cli_key(&path)?;
+        let path = String::from_utf8(key.clone()).unwrap();
+        let data = self.db.find_version(&key, &hash)
+            .and_then(|version| self.db.get_content(&version.hash))
+            .ok_or_else(|| CliError::new(
+                CliErrorKind::NotFound, format!("No single version of {:?} matching {:?}", path, hash)))?;
+        let fp = self.roots.from_key(&key).expect("Control path to be under a root");
+        if let Some(parent) = fp.to_path().parent() {
+            _ = std::fs::create_dir_all(parent);
+        }
+        std::fs::write(fp.to_path(), &data).map_err(|e| CliError::new(
+            CliErrorKind::Io, format!("Unable to restore {:?}: {}", path, e)))?;
+        self.local_change(fp.to_path().to_path_buf(), false);
+        Ok(CliResponse::Done { message: format!("Restored {:?} to version {}", path, hash) })
+    }
+
+    /// TOMBSTONES
+    // reconcile a key when the DHT entry or the local entry marks a deletion
+    fn reconcile_tombstone(&mut self, key: Vec<u8>, dht_entry: DhtEntry, peer: Option<PeerId>) {
+        let fp = match self.roots.from_key(&key) {
+            Some(fp) => fp,
+            None => return
+        };
+        if self.ignored(&fp) {
+            return
+        }
+        let local_entry = self.db.get(key.clone())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()));
+        if !dht_entry.deleted {
+            // only the local entry is a tombstone
+            let local_entry = local_entry.unwrap();
+            if dht_entry.timestamp > local_entry.timestamp {
+                if let Some(peer) = peer {
+                    self.request_file(&peer, &key, dht_entry);
+                    println!("Requesting {:?} as created again after deletion", fp.to_key());
+                }
+            } else {
+                add_to_dht(
+                    &mut self.swarm.behaviour_mut().kademlia,
+                    &self.group,
+                    fp.to_key(),
+                    local_entry);
+                println!("Pushed local tombstone for {:?} to dht", fp.to_key());
+            }
+            return
+        }
+        match local_entry {
+            Some(local_entry) if local_entry.deleted => {
+                // both deleted, keep the most recent tombstone
+                if dht_entry.timestamp > local_entry.timestamp {
+                    self.db.insert(key.clone(), dht_entry.to_bytes());
+                    self.ack_tombstone(key, dht_entry.timestamp);
+                } else if local_entry.timestamp > dht_entry.timestamp {
+                    add_to_dht(
+                        &mut self.swarm.behaviour_mut().kademlia,
+                        &self.group,
+                        fp.to_key(),
+                        local_entry);
+                }
+            },
+            Some(local_entry) if local_entry.timestamp >= dht_entry.timestamp => {
+                // local version is newer than the deletion, put it back in the DHT
+                add_to_dht(
+                    &mut self.swarm.behaviour_mut().kademlia,
+                    &self.group,
+                    fp.to_key(),
+                    local_entry);
+                println!("Local version of {:?} newer than dht tombstone", fp.to_key());
+            },
+            local_entry => {
+                let synced_hash = local_entry.as_ref().map(|entry| entry.current.clone());
+                match self.db.hash_file(&fp) {
+                    Some(current_hash) if Some(&current_hash) != synced_hash.as_ref()
+                        && current_hash != dht_entry.current => {
+                        // unsynced local edits, keep them as a new version
+                        println!("Not removing {:?} as it has local changes", fp.to_key());
+                        self.local_change(fp.to_path().to_path_buf(), local_entry.is_none());
+                        return
+                    },
+                    Some(_) => {
+                        if let Err(e) = std::fs::remove_file(fp.to_path()) {
+                            println!("Unable to remove {:?}: {:?}", fp.to_key(), e);
+                            return
+                        }
+                        println!("Removed {:?} as removed by peer", fp.to_key());
+                    },
+                    None => {}
+                }
+                self.key_2_filepath.remove(&key);
+                // the watcher event for the removal finds the tombstone and is ignored
+                self.db.insert(key.clone(), dht_entry.to_bytes());
+                self.ack_tombstone(key, dht_entry.timestamp);
+            }
+        }
+    }
+
+    fn ack_tombstone(&mut self, key: Vec<u8>, timestamp: i64) {
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        self.db.add_ack(key.clone(), timestamp, peerid.clone());
+        self.publish(nm::Messages::RemovedAck {
+            path: String::from_utf8(key.clone()).unwrap(),
+            timestamp,
+            peerid
+        });
+        self.collect_tombstone(key);
+    }
+
+    fn handle_removed_ack(&mut self, path: String, timestamp: i64, peerid: Vec<u8>) {
+        let key = path.as_bytes().to_vec();
+        // kept even when the tombstone hasn't reached us yet, the ack isn't sent again
+        self.db.add_ack(key.clone(), timestamp, peerid);
+        self.collect_tombstone(key);
+    }
+
+    // a tombstone is dropped once every paired peer seen lately has acknowledged it
+    fn collect_tombstone(&mut self, key: Vec<u8>) {
+        let timestamp = match self.db.get(key.clone()).and_then(|entry| DhtEntry::from_bytes(entry.to_vec())) {
+            Some(entry) if entry.deleted => entry.timestamp,
+            _ => return
+        };
+        let connected: Vec<PeerId> = self.swarm.connected_peers().cloned().collect();
+        for peer in connected {
+            if self.is_trusted(&peer) {
+                self.db.add_known_peer(peer.to_bytes());
+            }
+        }
+        self.db.prune_known_peers(Utc::now().timestamp() - PEER_TTL);
+        let acks = self.db.acks(key.clone(), timestamp);
+        let local_peer_id = self.swarm.local_peer_id().to_bytes();
+        let acked = acks.contains(&local_peer_id) &&
+            self.db.known_peers().iter().all(|peer| acks.contains(peer));
+        if acked {
+            self.db.remove(key.clone());
+            self.db.remove_acks(key.clone());
+            self.swarm.behaviour_mut().kademlia.remove_record(&record_key(&self.group, &key));
+            println!("Tombstone for {:?} acknowledged by all peers, removed", String::from_utf8(key));
+        }
+    }
+
+    /// PAIRING
+    // one device shows a code and both run a SPAKE2 exchange with it, each side
+    // confirms it derived the same key before trusting the other
+    fn is_trusted(&mut self, peer: &PeerId) -> bool {
+        self.db.is_trusted(&peer.to_bytes())
+    }
+
+    fn pairing(&self) -> bool {
+        let live = |pending: &Option<(String, Instant)>| pending.as_ref()
+            .map_or(false, |(_, started)| started.elapsed() < PAIR_WINDOW);
+        live(&self.pair_code) || live(&self.pair_entered)
+            || self.pair_accepted.as_ref().map_or(false, |(_, _, _, started)| started.elapsed() < PAIR_WINDOW)
+    }
+
+    // strangers are only kept connected while pairing, otherwise they sit in the
+    // gossipsub mesh and can ask the DHT for records
+    fn drop_strangers(&mut self) {
+        if self.pairing() {
+            return
+        }
+        let strangers: Vec<PeerId> = self.swarm.connected_peers().cloned().collect();
+        for peer in strangers {
+            if !self.is_trusted(&peer) {
+                println!("Disconnecting unpaired peer {:?}", peer);
+                _ = self.swarm.disconnect_peer_id(peer);
+            }
+        }
+    }
+
+    fn expire_pairing_later(&mut self) {
+        let mut sender = self.commandsender.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(PAIR_WINDOW).await;
+            _ = sender.send(Command::PairingExpired).await;
+        });
+    }
+
+    // connect to unpaired peers found on the network so pairing messages reach them
+    fn dial_discovered(&mut self) {
+        let strangers: Vec<(PeerId, Multiaddr)> = self.strangers.iter()
+            .map(|(peer, addr)| (*peer, addr.clone()))
+            .collect();
+        for (peer, addr) in strangers {
+            if !self.is_trusted(&peer) && !self.swarm.is_connected(&peer) {
+                _ = self.swarm.dial(addr);
+            }
+        }
+    }
+
+    fn show_pair_code(&mut self) -> CliReply {
+        let mut rng = rand::thread_rng();
+        let code: String = (0..8)
+            .map(|_| PAIR_ALPHABET[rng.gen_range(0..PAIR_ALPHABET.len())] as char)
+            .collect();
+        self.pair_code = Some((code.clone(), Instant::now()));
+        self.pair_accepted = None;
+        self.dial_discovered();
+        self.expire_pairing_later();
+        Ok(CliResponse::PairCode {
+            code: format!("{}-{}", &code[..4], &code[4..]),
+            expires_in: PAIR_WINDOW.as_secs()
+        })
+    }
+
+    fn enter_pair_code(&mut self, code: String) -> CliReply {
+        let code: String = code.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if code.len() != 8 {
+            return Err(CliError::new(CliErrorKind::BadRequest, "Pairing codes are 8 characters".into()))
+        }
+        self.pair_exchange = Some(PairExchange::start(&code));
+        self.pair_entered = Some((code, Instant::now()));
+        self.dial_discovered();
+        self.expire_pairing_later();
+        self.publish_pair_request();
+        Ok(CliResponse::Done { message: "Waiting for the other device to accept".into() })
+    }
+
+    fn publish_pair_request(&mut self) {
+        let message = match (&self.pair_entered, &self.pair_exchange) {
+            (Some((_, started)), Some(exchange)) if started.elapsed() < PAIR_WINDOW => exchange.message(),
+            _ => return
+        };
+        self.publish_plain(nm::Messages::PairRequest {
+            message,
+            // repeats would otherwise be dropped as duplicates
+            timestamp: Utc::now().timestamp_nanos(),
+            peerid: self.swarm.local_peer_id().to_bytes()
+        });
+    }
+
+    fn handle_pair_request(&mut self, source: PeerId, message: Vec<u8>) {
+        if self.is_revoked(&source) {
+            println!("Refused pairing from revoked peer {:?}", source);
+            return
+        }
+        let code = match &self.pair_code {
+            Some((code, started)) if started.elapsed() < PAIR_WINDOW => code.clone(),
+            _ => return
+        };
+        // the code is single use, whoever answers first gets the only guess at it
+        self.pair_code = None;
+        let exchange = PairExchange::start(&code);
+        let ours = exchange.message();
+        let key = match exchange.finish(&message) {
+            Some(key) => key,
+            None => {
+                println!("Malformed pairing request from {:?}", source);
+                return
+            }
+        };
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        let transcript = nm::pair_transcript(&peerid, &source.to_bytes(), &ours, &message);
+        // the group key is only readable with the exchanged key, so only with the right code
+        self.publish_plain(nm::Messages::PairAccept {
+            to: source.to_bytes(),
+            message: ours,
+            confirm: key.confirm(b"shower", &transcript),
+            group: key.wrap_group(&self.group),
+            peerid
+        });
+        self.pair_accepted = Some((source, key, transcript, Instant::now()));
+    }
+
+    fn handle_pair_accept(&mut self, source: PeerId, to: Vec<u8>, message: Vec<u8>, confirm: Vec<u8>, group: Vec<u8>) {
+        if to != self.swarm.local_peer_id().to_bytes() {
+            return
+        }
+        match &self.pair_entered {
+            Some((_, started)) if started.elapsed() < PAIR_WINDOW => (),
+            _ => return
+        };
+        let exchange = match self.pair_exchange.take() {
+            Some(exchange) => exchange,
+            None => return
+        };
+        // one attempt per entered code, a wrong code means entering a new one
+        self.pair_entered = None;
+        let ours = exchange.message();
+        let key = match exchange.finish(&message) {
+            Some(key) => key,
+            None => {
+                println!("Malformed pairing accept from {:?}", source);
+                return
+            }
+        };
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        let transcript = nm::pair_transcript(&source.to_bytes(), &peerid, &message, &ours);
+        if !key.verify(b"shower", &transcript, &confirm) {
+            println!("Pairing with {:?} failed, the codes don't match", source);
+            return
+        }
+        let group = match key.unwrap_group(&group) {
+            Some(group) => group,
+            None => {
+                println!("Unable to unwrap the group key from {:?}", source);
+                return
+            }
+        };
+        self.publish_plain(nm::Messages::PairConfirm {
+            to: source.to_bytes(),
+            confirm: key.confirm(b"enterer", &transcript),
+            peerid
+        });
+        let others = self.db.trusted_peers().into_iter()
+            .any(|peer| peer != source.to_bytes());
+        self.trust_peer(source, key.link());
+        if others {
+            // the devices already paired here would be left on the old key, so the two
+            // groups move to a fresh key which is handed to every device in both
+            let fresh = group.rotate(self.group.exclude.clone());
+            self.join_group(fresh);
+            self.hand_over_group(&[]);
+        } else {
+            self.join_group(group);
+        }
+    }
+
+    // each paired device gets the group key sealed with its own link key
+    fn hand_over_group(&mut self, except: &[PeerId]) {
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        for peer in self.db.trusted_peers() {
+            if except.iter().any(|except| except.to_bytes() == peer) || self.group.exclude.contains(&peer) {
+                continue
+            }
+            match self.db.link_key(&peer).and_then(|link| LinkKey::from_bytes(&link)) {
+                Some(link) => self.publish_plain(nm::Messages::GroupKey {
+                    to: peer,
+                    wrapped: link.wrap_group(&self.group),
+                    peerid: peerid.clone()
+                }),
+                None => println!(
+                    "WARNING: {:?} was paired by an older version and can't be sent the new group key, pair it again",
+                    PeerId::from_bytes(&peer).ok())
+            }
+        }
+    }
+
+    fn handle_group_key(&mut self, source: PeerId, to: Vec<u8>, wrapped: Vec<u8>) {
+        if to != self.swarm.local_peer_id().to_bytes() || !self.is_trusted(&source) {
+            return
+        }
+        let group = match self.db.link_key(&source.to_bytes())
+            .and_then(|link| LinkKey::from_bytes(&link))
+            .and_then(|link| link.unwrap_group(&wrapped)) {
+            Some(group) => group,
+            None => {
+                println!("WARNING: unable to open the group key sent by paired device {:?}", source);
+                return
+            }
+        };
+        if group.newer_than(&self.group) {
+            // passed on so devices not paired with the source get it too
+            self.join_group(group);
+            self.hand_over_group(&[source]);
+        } else if self.group.newer_than(&group) {
+            // the source missed a change, it catches up from us
+            self.send_group_key(source);
+        }
+    }
+
+    fn send_group_key(&mut self, peer: PeerId) {
+        if self.group.exclude.contains(&peer.to_bytes()) {
+            return
+        }
+        if let Some(link) = self.db.link_key(&peer.to_bytes()).and_then(|link| LinkKey::from_bytes(&link)) {
+            self.publish_plain(nm::Messages::GroupKey {
+                to: peer.to_bytes(),
+                wrapped: link.wrap_group(&self.group),
+                peerid: self.swarm.local_peer_id().to_bytes()
+            });
+        }
+    }
+
+    fn handle_pair_confirm(&mut self, source: PeerId, to: Vec<u8>, confirm: Vec<u8>) {
+        if to != self.swarm.local_peer_id().to_bytes() {
+            return
+        }
+        let (peer, key, transcript, started) = match self.pair_accepted.take() {
+            Some(accepted) => accepted,
+            None => return
+        };
+        if peer != source || started.elapsed() >= PAIR_WINDOW {
+            self.pair_accepted = Some((peer, key, transcript, started));
+            return
+        }
+        if !key.verify(b"enterer", &transcript, &confirm) {
+            println!("Pairing with {:?} failed, the codes don't match", source);
+            return
+        }
+        self.trust_peer(source, key.link());
+        // a key the new device rotated to may have arrived before it was trusted here
+        self.send_group_key(source);
+    }
+
+    fn is_revoked(&mut self, peer: &PeerId) -> bool {
+        self.db.is_revoked(&peer.to_bytes())
+    }
+
+    // drop a device everywhere it was trusted, the group moves to a key it doesn't hold
+    // and every record is sealed again with it
+    fn revoke_peer(&mut self, peer: PeerId, timestamp: i64) {
+        if peer == *self.swarm.local_peer_id() || self.is_revoked(&peer) {
+            return
+        }
+        println!("Revoking {:?}", peer);
+        self.forget_peer(peer, timestamp);
+        // sent under the old key, the other devices haven't got the new one yet
+        let peerid = self.swarm.local_peer_id().to_bytes();
+        self.publish(nm::Messages::Revoked { peer: peer.to_bytes(), timestamp, peerid });
+        let fresh = self.group.rotate(vec![peer.to_bytes()]);
+        self.join_group(fresh);
+        self.hand_over_group(&[]);
+    }
+
+    // stops trusting a device here without telling anyone
+    fn forget_peer(&mut self, peer: PeerId, timestamp: i64) {
+        self.db.revoke_peer(peer.to_bytes(), timestamp);
+        self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer);
+        self.swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+        self.swarm.behaviour_mut().kademlia.remove_peer(&peer);
+        self.swarm.ban_peer_id(peer);
+    }
+
+    // applied straight away, a revoked device still holding a link key with this one
+    // could otherwise hand it a newer group key and be let back in everywhere.
+    // the revoking device rotates the group key and hands it over itself
+    fn revocation_received(&mut self, source: PeerId, peer: Vec<u8>, timestamp: i64) {
+        if peer == self.swarm.local_peer_id().to_bytes() {
+            println!("WARNING: {:?} revoked this device, ignoring", source);
+            return
+        }
+        if peer == source.to_bytes() || self.db.is_revoked(&peer) {
+            return
+        }
+        let revoked = match PeerId::from_bytes(&peer) {
+            Ok(revoked) => revoked,
+            Err(_) => {
+                println!("Malformed revocation from {:?}", source);
+                return
+            }
+        };
+        println!("{:?} revoked {:?}, no longer trusting it", source, revoked);
+        self.forget_peer(revoked, timestamp);
+    }
+
+    fn revoke(&mut self, peer: String) -> CliReply {
+        let peer: PeerId = peer.parse().map_err(|_| CliError::new(
+            CliErrorKind::BadRequest, format!("{:?} is not a peer id", peer)))?;
+        if peer == *self.swarm.local_peer_id() {
+            return Err(CliError::new(CliErrorKind::BadRequest, "Can't revoke this device".into()))
+        }
+        if self.is_revoked(&peer) {
+            return Ok(CliResponse::Done { message: format!("{} is already revoked", peer) })
+        }
+        self.revoke_peer(peer, Utc::now().timestamp());
+        Ok(CliResponse::Done { message: format!("Revoked {}", peer) })
+    }
+
+    fn unrevoke(&mut self, peer: String) -> CliReply {
+        let peer: PeerId = peer.parse().map_err(|_| CliError::new(
+            CliErrorKind::BadRequest, format!("{:?} is not a peer id", peer)))?;
+        if !self.is_revoked(&peer) {
+            return Err(CliError::new(CliErrorKind::NotFound, format!("{} isn't revoked", peer)))
+        }
+        println!("Unrevoking {:?}", peer);
+        self.db.unrevoke_peer(&peer.to_bytes());
+        self.swarm.unban_peer_id(peer);
+        self.swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer);
+        Ok(CliResponse::Done { message: format!("Unrevoked {}, pair with it again to sync", peer) })
+    }
+
+    // the database key stays the same, only the copy wrapped with the secret is rewritten
+    fn rekey(&mut self, current: DbSecret, new: DbSecret) -> CliReply {
+        if !crypto::db_encrypted(&config_dir()) {
+            return Err(CliError::new(CliErrorKind::BadRequest, "Database isn't encrypted".into()))
+        }
+        let read = |secret: DbSecret| secret.to_bytes().map_err(|e| CliError::new(
+            CliErrorKind::Io, format!("Unable to read key file: {}", e)));
+        match crypto::rekey_db(&config_dir(), &read(current)?, &read(new)?) {
+            Some(Ok(())) => Ok(CliResponse::Done { message: "Database key protected with the new secret".into() }),
+            Some(Err(e)) => Err(CliError::new(CliErrorKind::Io, format!("Unable to write database key: {}", e))),
+            None => Err(CliError::new(CliErrorKind::PermissionDenied, "Wrong passphrase or key file".into()))
+        }
+    }
+
+    // records sealed with the old key can't be read any more, ours are put back on sync
+    fn join_group(&mut self, group: GroupKey) {
+        group.save(&mut self.db);
+        self.group = group;
+        self.dht_keys.clear();
+        let unreadable: Vec<Key> = self.swarm.behaviour_mut().kademlia.store_mut().records()
+            .filter(|record| open_record(&self.group, record).is_none())
+            .map(|record| record.key.clone())
+            .collect();
+        for record_key in unreadable {
+            self.swarm.behaviour_mut().kademlia.store_mut().remove(&record_key);
+        }
+        for (key, _) in self.db.entries() {
+            self.sync_pending.insert(key, SyncAction::Publish { added: true });
+        }
+        println!("Switched to the group key from {}", self.group.epoch);
+    }
+
+    fn trust_peer(&mut self, peer: PeerId, link: LinkKey) {
+        println!("Paired with {:?}", peer);
+        self.db.trust_peer(peer.to_bytes(), link.to_bytes());
+        // paired again after the group key was rotated away from it
+        if self.group.exclude.contains(&peer.to_bytes()) {
+            self.group.exclude.retain(|excluded| *excluded != peer.to_bytes());
+            self.group.save(&mut self.db);
+        }
+        self.db.add_known_peer(peer.to_bytes());
+        self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+        for addr in self.swarm.behaviour_mut().mdns.addresses_of_peer(&peer) {
+            self.swarm.behaviour_mut().kademlia.add_address(&peer, addr);
+        }
+        if let Some(addr) = self.strangers.remove(&peer) {
+            self.swarm.behaviour_mut().kademlia.add_address(&peer, addr);
+        }
+        // sent from a task, the loop can't wait on its own full queue
+        let mut sender = self.commandsender.clone();
+        async_std::task::spawn(async move {
+            sender.send(Command::NewPeer).await
+                .expect("Command reciever not to be dropped");
+        });
+    }
+
+    /// CONTROL
+    // paths from the control socket are keys, or local files under a root
+    fn cli_key(&self, path: &String) -> Result<Vec<u8>, CliError> {
+        let fp = self.roots.from_key(path.as_bytes())
+            .or_else(|| std::fs::canonicalize(path).ok().and_then(|full| self.roots.from_path(full)))
+            .ok_or_else(|| CliError::new(CliErrorKind::NotFound, format!("{:?} is not under a synced root", path)))?;
+        Ok(fp.to_bytes())
+    }
+
+    fn status(&mut self) -> CliReply {
+        let entries = self.db.entries();
+        Ok(CliResponse::Status {
+            peer_id: self.swarm.local_peer_id().to_base58(),
+            paused: self.paused,
+            connected_peers: self.swarm.connected_peers().count(),
+            files: entries.iter().filter(|(_, entry)| !entry.deleted).count(),
+            conflicts: self.db.conflicts().len(),
+            pending: self.sync_pending.len(),
+            roots: self.roots.roots.iter()
+                .map(|(name, dir)| (name.clone(), dir.to_string_lossy().to_string()))
+                .collect()
+        })
+    }
+
+    // peers seen over mdns and any connected some other way
+    fn list_peers(&mut self) -> CliReply {
+        let connected: HashSet<PeerId> = self.swarm.connected_peers().cloned().collect();
+        let mut known: Vec<PeerId> = self.swarm.behaviour().mdns.discovered_nodes().cloned().collect();
+        known.extend(connected.iter().cloned());
+        known.extend(self.db.trusted_peers().iter().filter_map(|peer| PeerId::from_bytes(peer).ok()));
+        known.extend(self.db.revoked_peers().iter().filter_map(|(peer, _)| PeerId::from_bytes(peer).ok()));
+        known.sort_by_key(|peer| peer.to_base58());
+        known.dedup();
+        Ok(CliResponse::Peers {
+            peers: known.into_iter()
+                .map(|peer| PeerInfo {
+                    peer_id: peer.to_base58(),
+                    connected: connected.contains(&peer),
+                    trusted: self.db.is_trusted(&peer.to_bytes()),
+                    revoked: self.db.is_revoked(&peer.to_bytes())
+                })
+                .collect()
+        })
+    }
+
+    fn list_files(&mut self) -> CliReply {
+        let mut files: Vec<FileInfo> = self.db.entries().into_iter()
+            .map(|(key, entry)| FileInfo {
+                path: String::from_utf8_lossy(&key).to_string(),
+                hash: entry.current,
+                timestamp: entry.timestamp,
+                deleted: entry.deleted
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(CliResponse::Files { files })
+    }
+
+    // announce local versions, peers GET the record and fetch when it is newer
+    fn push(&mut self, path: Option<String>) -> CliReply {
+        let keys = match path {
+            Some(path) => vec![self.cli_key(&path)?],
+            None => self.db.entries().into_iter().map(|(key, _)| key).collect()
+        };
+        let count = keys.len();
+        for key in keys {
+            self.sync_pending.insert(key, SyncAction::Publish { added: true });
+        }
+        self.sync();
+        Ok(CliResponse::Done { message: format!("Pushed {} files to peers", count) })
+    }
+
+    fn pull(&mut self, path: Option<String>) -> CliReply {
+        match path {
+            Some(path) => {
+                let key = self.cli_key(&path)?;
+                self.dht_get(&key);
+                Ok(CliResponse::Done { message: format!("Checking {:?} with peers", String::from_utf8_lossy(&key)) })
+            },
+            None => {
                 self.add_peer_check();
-                println!("{:?}", &path);
+                Ok(CliResponse::Done { message: format!("Checking {} files with peers", self.key_2_filepath.len()) })
+            }
+        }
+    }
+
+    async fn resume(&mut self) -> CliReply {
+        if !self.paused {
+            return Ok(CliResponse::Done { message: "Already syncing".into() })
+        }
+        self.paused = false;
+        let held: Vec<Command> = self.held_events.drain(..).collect();
+        println!("Replaying {} file events held while paused", held.len());
+        for watch in held {
+            self.handle_watch(watch).await;
+        }
+        // anything the watcher missed, then remote changes missed while paused
+        self.check_local_db();
+        self.add_peer_check();
+        Ok(CliResponse::Done { message: "Resumed syncing".into() })
+    }
+
+    async fn handle_cli(&mut self, command: CliCommand) -> CliReply {
+        println!("CLI command {:?}", &command);
+        match command {
+            CliCommand::Status => self.status(),
+            CliCommand::Peers => self.list_peers(),
+            CliCommand::Files => self.list_files(),
+            CliCommand::Push { path } => self.push(path),
+            CliCommand::Pull { path } => self.pull(path),
+            CliCommand::Pause => {
+                self.paused = true;
+                Ok(CliResponse::Done { message: "Paused syncing".into() })
             },
-            _ => {println!("unhandled")}
+            CliCommand::Resume => self.resume().await,
+            CliCommand::Pair { code: None } => self.show_pair_code(),
+            CliCommand::Pair { code: Some(code) } => self.enter_pair_code(code),
+            CliCommand::Revoke { peer } => self.revoke(peer),
+            CliCommand::Unrevoke { peer } => self.unrevoke(peer),
+            // the daemon only gets this far once the database is open
+            CliCommand::Unlock { .. } if crypto::db_encrypted(&config_dir()) => Ok(CliResponse::Done { message: "Database is already unlocked".into() }),
+            CliCommand::Unlock { .. } => Err(CliError::new(
+                CliErrorKind::BadRequest, "Database isn't encrypted, set encrypt_db in the config and restart".into())),
+            CliCommand::Rekey { current, new } => self.rekey(current, new),
+            CliCommand::Conflicts => self.list_conflicts(),
+            CliCommand::Resolve { path, keep } => self.resolve_conflict(path, keep),
+            CliCommand::History { path } => self.list_history(path),
+            CliCommand::Show { path, hash } => self.show_version(path, hash),
+            CliCommand::Restore { path, hash } => self.restore_version(path, hash),
         }
     }
 
@@ -434,6 +2195,10 @@ impl NetworkEvent {
                 println!("Entered command newpeer");
                 self.add_peer_check();
             },
+            Command::SettleDelete{path} => {
+                self.settle_delete(path);
+            },
+            Command::PairingExpired => self.drop_strangers(),
             _ => {println!("unhandled")}
         }
     }
@@ -453,13 +2218,61 @@ impl NetworkEvent {
             SwarmEvent::NewListenAddr { address, .. } => {
                 println!("Listening on {:?}", address);
             },
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                if self.is_trusted(&peer_id) {
+                    self.db.add_known_peer(peer_id.to_bytes());
+                } else if !self.pairing() {
+                    println!("Disconnecting unpaired peer {:?}", peer_id);
+                    _ = self.swarm.disconnect_peer_id(peer_id);
+                }
+            },
             SwarmEvent::Behaviour(OrgBehaviourEvent::Ping(ping)) => println!("{:?}", ping),
             SwarmEvent::Behaviour(OrgBehaviourEvent::Gossipsub(GossipsubEvent::Message {
                 propagation_source: peer_id,
                 message_id: id,
                 message,
             })) => {
-                let msg: nm::Messages = bendy::serde::from_bytes::<nm::Messages>(&message.data).unwrap();
+                // peers are asked for newer versions again on resume
+                if self.paused {
+                    return
+                }
+                // messages are signed, so the source is the device that wrote it
+                let source = match message.source {
+                    Some(source) => source,
+                    None => return
+                };
+                let msg = match self.group.open(&message.data).and_then(nm::from_bytes) {
+                    Some(msg) if self.is_trusted(&source) => msg,
+                    Some(_) => {
+                        println!("Ignoring message from unpaired peer {:?}", source);
+                        return
+                    },
+                    // only pairing messages are sent before a device has the group key
+                    None => {
+                        match nm::from_bytes(message.data.clone()) {
+                            Some(nm::Messages::PairRequest { message, .. }) => {
+                                self.handle_pair_request(source, message)
+                            },
+                            Some(nm::Messages::PairAccept { to, message, confirm, group, .. }) => {
+                                self.handle_pair_accept(source, to, message, confirm, group)
+                            },
+                            Some(nm::Messages::PairConfirm { to, confirm, .. }) => {
+                                self.handle_pair_confirm(source, to, confirm)
+                            },
+                            Some(nm::Messages::GroupKey { to, wrapped, .. }) => {
+                                self.handle_group_key(source, to, wrapped)
+                            },
+                            // a paired device on another group key is cut off until the keys meet again
+                            _ if self.is_trusted(&source) => println!(
+                                "WARNING: unable to open a message from paired device {:?}, it holds a different group key",
+                                source),
+                            _ => println!("Unreadable message from {:?}", source)
+                        }
+                        // the exchange may have just finished one way or the other
+                        self.drop_strangers();
+                        return
+                    }
+                };
                 println!(
                     "Got message: {:?} with id: {} from peer: {:?}",
                     &msg,
@@ -468,22 +2281,22 @@ impl NetworkEvent {
                 );
                 // TODO add message logic
                 match msg {
-                    nm::Messages::Added { path, peerid } => {
-                        // add file to transfer pending
-                        // trigger file transfer request/response
-                        // file transferred and saved to disk
-                        // watcher triggers for new file
-                        // handle_watcher checks transfer pending and removes entry (msg not sent)
-                        // update entry to local / sync with dht
+                    nm::Messages::Added { path, .. } => {
+                        // GET on the key reconciles against the local db
+                        // and requests the file from the publisher when newer
+                        // the written file is recognised by its hash in handle_watch
+                        self.dht_get(path.as_bytes());
                     },
-                    nm::Messages::Changed { path, patch, peerid } => {
-                        // check previous hash is equal to current hash on disk
-                        // add file to transfer pending
-                        // if true apply patch and save to disk
-                        // if false trigger file transfer
-                        // watcher triggers new file or change
-                        // handler check transfer pending and removes entry (msg not sent)
-                        // update entry to local / sync with dht
+                    nm::Messages::Changed { path, patch, previous, current, timestamp, peerid, origin } => {
+                        let entry = DhtEntry {
+                            current,
+                            previous: Some(previous).filter(|previous| !previous.is_empty()),
+                            timestamp,
+                            deleted: false,
+                            origin: if origin.is_empty() { peerid.clone() } else { origin }
+                        };
+                        let peer = PeerId::from_bytes(&peerid).ok();
+                        self.remote_change(path, patch, entry, peer);
                     },
                     nm::Messages::Pushed { path, peerid } => {
                         // this is a force push of state from peer
@@ -491,17 +2304,40 @@ impl NetworkEvent {
                         // request all files from peer
                         // watcher triggered and removes transfer pending
                     },
-                    nm::Messages::Removed { path, .. } => {
-                        // add file to transfer pending
-                        // remove file
-                        // watcher notes removed file and takes of transfer pending
+                    nm::Messages::Removed { path, hash, timestamp, peerid } => {
+                        let tombstone = DhtEntry {
+                            current: hash,
+                            previous: None,
+                            timestamp,
+                            deleted: true,
+                            origin: peerid.clone()
+                        };
+                        let peer = PeerId::from_bytes(&peerid).ok();
+                        // the device that deleted it holds the tombstone already
+                        self.db.add_ack(path.as_bytes().to_vec(), timestamp, peerid);
+                        self.reconcile_tombstone(path.as_bytes().to_vec(), tombstone, peer);
+                    },
+                    nm::Messages::Moved { from, to, previous, current, timestamp, peerid } => {
+                        let entry = DhtEntry {
+                            current,
+                            previous: Some(previous),
+                            timestamp,
+                            deleted: false,
+                            origin: peerid.clone()
+                        };
+                        let peer = PeerId::from_bytes(&peerid).ok();
+                        self.db.add_ack(from.as_bytes().to_vec(), timestamp, peerid);
+                        self.remote_move(from, to, entry, peer);
+                    },
+                    nm::Messages::RemovedAck { path, timestamp, peerid } => {
+                        self.handle_removed_ack(path, timestamp, peerid);
+                    },
+                    nm::Messages::Revoked { peer, timestamp, .. } => {
+                        self.revocation_received(source, peer, timestamp)
                     },
                     nm::Messages::FileCheck { filepath, timestamp } => {
-                        let fp = FilePath::struct_from_bytes(filepath).unwrap();
-                        let key = Key::new(&fp.to_bytes());
-                        self.swarm.behaviour_mut().kademlia.get_record(
-                            key, Quorum::One);
-                        println!("FileCheck msg for {:?}", fp.sub_home())
+                        self.dht_get(&filepath);
+                        println!("FileCheck msg for {:?}", String::from_utf8(filepath))
                     }
                     _ => {}
                 }
@@ -509,13 +2345,26 @@ impl NetworkEvent {
             SwarmEvent::Behaviour(OrgBehaviourEvent::Mdns(MdnsEvent::Discovered(list))) => {
                 println!("Found peer(s)");
                 for (peer_id, multiaddr) in list {
+                    if !self.is_trusted(&peer_id) {
+                        // strangers are only connected to while pairing
+                        if self.pairing() {
+                            _ = self.swarm.dial(multiaddr.clone());
+                        }
+                        self.strangers.insert(peer_id, multiaddr);
+                        println!("Found unpaired peer: {:?}", peer_id);
+                        continue
+                    }
+                    self.db.add_known_peer(peer_id.to_bytes());
                     self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
                     self.swarm.behaviour_mut().kademlia.add_address(&peer_id, multiaddr);
                     println!("Added peer: {:?}", peer_id)
                 }
-                self.commandsender.try_send(
-                    Command::NewPeer
-                ).expect("Command reciever not to be dropped");
+                // sent from a task, the loop can't wait on its own full queue
+                let mut sender = self.commandsender.clone();
+                async_std::task::spawn(async move {
+                    sender.send(Command::NewPeer).await
+                        .expect("Command reciever not to be dropped");
+                });
             },
             SwarmEvent::Behaviour(OrgBehaviourEvent::Mdns(MdnsEvent::Expired(list))) => {
                 for (peer_id, multiaddr) in list {
@@ -531,7 +2380,7 @@ impl NetworkEvent {
                         println!(
                             "Peer {:?} provides key {:?}",
                             peer,
-                            std::str::from_utf8(ok.key.as_ref()).unwrap()
+                            self.record_name(&ok.key)
                         );
                     }
                 }
@@ -543,7 +2392,7 @@ impl NetworkEvent {
                 QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
                     println!(
                         "Successfully put record {:?}",
-                        std::str::from_utf8(key.as_ref()).unwrap()
+                        self.record_name(&key)
                     );
                 }
                 QueryResult::PutRecord(Err(err)) => {
@@ -552,7 +2401,7 @@ impl NetworkEvent {
                 QueryResult::StartProviding(Ok(AddProviderOk { key })) => {
                     println!(
                         "Successfully put provider record {:?}",
-                        std::str::from_utf8(key.as_ref()).unwrap()
+                        self.record_name(&key)
                     );
                 }
                 QueryResult::StartProviding(Err(err)) => {
@@ -561,6 +2410,39 @@ impl NetworkEvent {
                 _ => {}
                 }
             },
+            SwarmEvent::Behaviour(OrgBehaviourEvent::Gossipsub(GossipsubEvent::Subscribed { peer_id, .. })) => {
+                // the device showing the code may only just have connected
+                if !self.is_trusted(&peer_id) {
+                    self.publish_pair_request();
+                } else {
+                    // a device that was offline when the key changed is caught up either way
+                    self.send_group_key(peer_id);
+                }
+            },
+            SwarmEvent::Behaviour(OrgBehaviourEvent::Kademlia(KademliaEvent::InboundRequest {
+                request: InboundRequest::PutRecord { source, record: Some(record), .. }
+            })) => {
+                if !self.is_trusted(&source) {
+                    println!("Refused record from unpaired peer {:?}", source);
+                    return
+                }
+                // a trusted peer may be replicating what a revoked one published
+                if record.publisher.map_or(false, |publisher| self.is_revoked(&publisher)) {
+                    println!("Refused record published by revoked peer {:?}", record.publisher);
+                    return
+                }
+                if let Err(e) = self.swarm.behaviour_mut().kademlia.store_mut().put(record) {
+                    println!("Unable to store record from {:?}: {:?}", source, e);
+                }
+            },
+            SwarmEvent::Behaviour(OrgBehaviourEvent::Kademlia(KademliaEvent::InboundRequest {
+                request: InboundRequest::AddProvider { record: Some(record) }
+            })) => {
+                if !self.is_trusted(&record.provider) {
+                    return
+                }
+                _ = self.swarm.behaviour_mut().kademlia.store_mut().add_provider(record);
+            },
             // TODO include request response behaviour
             // SwarmEvent::Behaviour(OrgBehaviourEvent::RequestResponse)
             SwarmEvent::Behaviour(OrgBehaviourEvent::RequestResponse(event)) => {
@@ -570,3 +2452,173 @@ impl NetworkEvent {
         }
     }
 }
+
+fn descent(db: &mut Database, local: &String, remote: &String) -> Descent {
+    if local == remote {
+        Descent::Same
+    } else if db.is_ancestor(local, remote) {
+        Descent::Newer
+    } else if db.is_ancestor(remote, local) {
+        Descent::Stale
+    } else {
+        Descent::Fork
+    }
+}
+
+/// Files on disk compared with the db, new versions and tombstones are recorded
+/// and the keys peers need to hear about are returned
+fn scan_local(
+    db: &mut Database,
+    roots: &Roots,
+    rules: &IgnoreRules,
+    files: Vec<FilePath>,
+    held: &HashSet<PathBuf>,
+    local_peer_id: &PeerId
+) -> Vec<(Vec<u8>, SyncAction)> {
+    let mut found = vec![];
+    let mut on_disk = HashSet::new();
+    for file in files {
+        let key = file.to_bytes();
+        on_disk.insert(key.clone());
+        let current_hash = match db.hash_file(&file) {
+            Some(hash) => hash,
+            None => continue
+        };
+        let local_entry = db.get(key.clone())
+            .and_then(|entry| DhtEntry::from_bytes(entry.to_vec()));
+        if let Some(entry) = &local_entry {
+            if entry.current == current_hash && !entry.deleted {
+                continue
+            }
+        }
+        let timestamp = std::fs::metadata(file.to_path())
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| DateTime::<Utc>::from(modified).timestamp())
+            .unwrap_or_else(|_| Utc::now().timestamp());
+        let new_entry = DhtEntry {
+            current: current_hash,
+            previous: local_entry.as_ref().map(|entry| entry.current.clone()),
+            timestamp,
+            deleted: false,
+            origin: local_peer_id.to_bytes()
+        };
+        db.insert(key.clone(), new_entry.to_bytes());
+        db.record_edit(&key, &new_entry);
+        if let Ok(data) = std::fs::read(file.to_path()) {
+            db.record_version(&key, &new_entry, &data);
+        }
+        let added = local_entry.map_or(true, |entry| entry.deleted);
+        println!("{} while offline {:?}", if added { "Added" } else { "Changed" }, file.to_key());
+        found.push((key, SyncAction::Publish { added }));
+    }
+    // an unmounted drive or a root not synced yet looks like every file was deleted
+    let mut unavailable = HashSet::new();
+    for (name, dir) in roots.roots.iter() {
+        if std::fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none()) {
+            println!("WARNING: root {:?} at {:?} is missing or empty, its files are not marked deleted", name, dir);
+            unavailable.insert(name.clone());
+        }
+    }
+    // a live entry is only written once its file is on disk, so a file the db
+    // holds but the disk lacks was deleted while the daemon was off
+    for (key, entry) in db.entries() {
+        if entry.deleted || on_disk.contains(&key)
+            || roots.from_key(&key).map_or(true, |fp| rules.is_ignored(fp.to_path()) || held.contains(fp.to_path())
+                || unavailable.contains(&fp.root)) {
+            continue
+        }
+        let tombstone = DhtEntry {
+            current: entry.current,
+            previous: entry.previous,
+            timestamp: Utc::now().timestamp(),
+            deleted: true,
+            origin: local_peer_id.to_bytes()
+        };
+        db.insert(key.clone(), tombstone.to_bytes());
+        db.add_ack(key.clone(), tombstone.timestamp, local_peer_id.to_bytes());
+        println!("Removed while offline {:?}", String::from_utf8_lossy(&key));
+        found.push((key, SyncAction::Remove));
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn notes() -> (tempfile::TempDir, Roots, IgnoreRules) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut roots = BTreeMap::new();
+        roots.insert("notes".to_string(), dir.path().to_path_buf());
+        let rules = IgnoreRules::new(vec![], vec![dir.path().to_path_buf()]);
+        (dir, Roots { roots }, rules)
+    }
+
+    fn files(roots: &Roots) -> Vec<FilePath> {
+        roots.dirs().into_iter()
+            .flat_map(|dir| WalkDir::new(dir).into_iter().filter_map(|e| e.ok()))
+            .filter(|entry| !entry.path().is_dir())
+            .filter_map(|entry| roots.from_path(entry.into_path()))
+            .collect()
+    }
+
+    #[test]
+    fn older_remote_versions_are_stale() {
+        let mut db = crate::db::temporary();
+        let (first, second, third, other) = ("first".to_string(), "second".to_string(),
+            "third".to_string(), "other".to_string());
+        db.record_ancestry(&second, &Some(first.clone()));
+        db.record_ancestry(&third, &Some(second.clone()));
+        db.record_ancestry(&other, &Some(first.clone()));
+        assert_eq!(descent(&mut db, &third, &first), Descent::Stale);
+        assert_eq!(descent(&mut db, &third, &second), Descent::Stale);
+        assert_eq!(descent(&mut db, &second, &third), Descent::Newer);
+        assert_eq!(descent(&mut db, &third, &other), Descent::Fork);
+        assert_eq!(descent(&mut db, &third, &third), Descent::Same);
+    }
+
+    #[test]
+    fn file_deleted_while_stopped_stays_deleted() {
+        let (dir, roots, rules) = notes();
+        let mut db = crate::db::temporary();
+        let peer = PeerId::random();
+        std::fs::write(dir.path().join("todo.org"), "* TODO call\n").unwrap();
+        let key = b"notes:/todo.org".to_vec();
+
+        let found = scan_local(&mut db, &roots, &rules, files(&roots), &HashSet::new(), &peer);
+        assert_eq!(found, vec![(key.clone(), SyncAction::Publish { added: true })]);
+        // anything else in the root keeps it from looking unmounted
+        std::fs::create_dir(dir.path().join("archive")).unwrap();
+
+        // the daemon is stopped when the file goes
+        std::fs::remove_file(dir.path().join("todo.org")).unwrap();
+        let found = scan_local(&mut db, &roots, &rules, files(&roots), &HashSet::new(), &peer);
+        assert_eq!(found, vec![(key.clone(), SyncAction::Remove)]);
+        let entry = DhtEntry::from_bytes(db.get(key.clone()).unwrap()).unwrap();
+        assert!(entry.deleted);
+
+        // later starts leave the tombstone alone rather than fetching the file again
+        let found = scan_local(&mut db, &roots, &rules, files(&roots), &HashSet::new(), &peer);
+        assert!(found.is_empty());
+        assert!(DhtEntry::from_bytes(db.get(key).unwrap()).unwrap().deleted);
+        assert!(!dir.path().join("todo.org").exists());
+    }
+
+    #[test]
+    fn files_of_a_missing_or_empty_root_are_kept() {
+        let (dir, roots, rules) = notes();
+        let mut db = crate::db::temporary();
+        let peer = PeerId::random();
+        std::fs::write(dir.path().join("todo.org"), "* TODO call\n").unwrap();
+        let key = b"notes:/todo.org".to_vec();
+        scan_local(&mut db, &roots, &rules, files(&roots), &HashSet::new(), &peer);
+
+        // the drive holding the root isn't mounted, the mount point is empty
+        std::fs::remove_file(dir.path().join("todo.org")).unwrap();
+        assert!(scan_local(&mut db, &roots, &rules, files(&roots), &HashSet::new(), &peer).is_empty());
+        std::fs::remove_dir(dir.path()).unwrap();
+        assert!(scan_local(&mut db, &roots, &rules, files(&roots), &HashSet::new(), &peer).is_empty());
+        assert!(!DhtEntry::from_bytes(db.get(key).unwrap()).unwrap().deleted);
+    }
+}
@@ -17,9 +17,9 @@ pub struct FileExchangeProtocol();
 #[derive(Clone)]
 pub struct FileExchangeCodec();
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct FileRequest(Vec<u8>);
+pub struct FileRequest(pub Vec<u8>);
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct FileResponse(Vec<u8>);
+pub struct FileResponse(pub Vec<u8>);
 
 impl ProtocolName for FileExchangeProtocol {
     fn protocol_name(&self) -> &[u8] {
@@ -98,10 +98,36 @@ impl RequestResponseCodec for FileExchangeCodec {
     }
 }
 
+// a request names the file key and the hash the requester expects to receive,
+// only that version is served unless any_version is set
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Eq)]
+pub struct RequestData {
+    pub key: Vec<u8>,
+    pub hash: String,
+    // reconciling and conflict copies take whatever version the peer has now
+    #[serde(default)]
+    pub any_version: bool,
+}
+
+impl RequestData {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bendy::serde::to_bytes(&self).unwrap()
+    }
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<Self> {
+        bendy::serde::from_bytes::<Self>(&bytes).ok()
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Eq)]
 pub struct ResponseData {
     pub metadata: DhtEntry,
     pub data: Vec<u8>,
+    // hashes from metadata.current back through its known previous versions
+    #[serde(default)]
+    pub ancestry: Vec<String>,
+    // (merge, parent) links for versions merged into that chain
+    #[serde(default)]
+    pub merges: Vec<(String, String)>,
 }
 
 impl  ResponseData{
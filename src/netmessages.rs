@@ -5,10 +5,30 @@ use bendy;
 pub enum Messages {
     Pushed { path: String, peerid: Vec<u8> },
     Added { path: String, peerid: Vec<u8> },
-    Changed { path: String, patch: String, peerid: Vec<u8> },
-    Removed { path: String, peerid: Vec<u8> },
+    // origin is the device that made the version, peerid the one announcing it
+    Changed { path: String, patch: String, previous: String, current: String, timestamp: i64, peerid: Vec<u8>,
+              #[serde(default)] origin: Vec<u8> },
+    Removed { path: String, hash: String, timestamp: i64, peerid: Vec<u8> },
+    Moved { from: String, to: String, previous: String, current: String, timestamp: i64, peerid: Vec<u8> },
+    RemovedAck { path: String, timestamp: i64, peerid: Vec<u8> },
     FileCheck { filepath: Vec<u8>, timestamp: i64 },
     FileUpdate { path: String, current: String, previous: String, timestamp: i64, data: Vec<u8> },
+    // sent by the device a pairing code was entered on, message is its SPAKE2 message
+    PairRequest { message: Vec<u8>, timestamp: i64, peerid: Vec<u8> },
+    // reply from the device showing the code with its SPAKE2 message, confirm proves it
+    // derived the key and group is the group key wrapped with that key
+    PairAccept { to: Vec<u8>, message: Vec<u8>, confirm: Vec<u8>, group: Vec<u8>, peerid: Vec<u8> },
+    // the entering device proves it derived the same key, only then is it trusted
+    PairConfirm { to: Vec<u8>, confirm: Vec<u8>, peerid: Vec<u8> },
+    // a group key handed to one paired device, sealed with the key kept from pairing with it
+    GroupKey { to: Vec<u8>, wrapped: Vec<u8>, peerid: Vec<u8> },
+    // a device removed from the trusted set, paired devices that receive it drop it too
+    Revoked { peer: Vec<u8>, timestamp: i64, peerid: Vec<u8> },
+}
+
+/// What both sides' confirmations cover, so a confirmation can't be replayed into another exchange
+pub fn pair_transcript(shower: &[u8], enterer: &[u8], shower_message: &[u8], enterer_message: &[u8]) -> Vec<u8> {
+    bendy::serde::to_bytes(&(shower, enterer, shower_message, enterer_message)).unwrap()
 }
 
 // test
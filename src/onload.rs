@@ -1,55 +1,63 @@
 use clap::Parser;
 use walkdir::{WalkDir};
-use crate::types::{Config, FilePath};
-use std::path::{PathBuf};
+use crate::types::{Config, FilePath, Roots};
+use crate::ignores::{IgnoreRules, SharedRules};
+use std::collections::BTreeMap;
 
 // function to handle arg iunput and load config to paths
-pub fn onload(args: Args) -> (Config, Vec<PathBuf>, Vec<FilePath>) {
+pub fn onload(args: Args) -> (Config, Roots, Vec<FilePath>, SharedRules) {
+    let config = load_config(&args.config);
+    let roots = Roots::from_config(&config)
+        .unwrap_or_else(|err| panic!("Invalid roots in config: {}", err));
+    let rules = IgnoreRules::shared(config.ignore.clone(), roots.dirs());
+    let mut paths: Vec<_> = vec![];
+    for dir in roots.dirs().into_iter() {
+        let walk = WalkDir::new(dir).into_iter()
+            .filter_entry(|e| !rules.read().unwrap().is_ignored(e.path()));
+        for entry in walk.filter_map(|e| e.ok()) {
+            if !entry.path().is_dir() {
+                paths.extend(roots.from_path(entry.into_path()));
+            }
+            // println!("{}", entry.path().display());
+        }
+    };
+    // println!("Files list: {:?}", &paths.into_iter().map(|x| x.to_key()).collect::<Vec<String>>());
+    (config, roots, paths, rules)
+}
+
+// config file is also read by queries to find the daemon
+pub fn load_config(config_path: &Option<String>) -> Config {
     // check for config file and import
-    // config file is a json object of type {"version": path, "listen": address, paths: ["path"]}
+    // config file is a json object of type
+    // {"version": path, "listen": address, paths: ["path"], roots: {"name": "path"}, ignore: ["pattern"],
+    //  debounce_ms: 300, control_tcp: "127.0.0.1:1324", psk: "hex key or path to swarm.key",
+    //  encrypt_db: false, db_key_file: "path to key file"}
     let config: Config;
-    let home = std::env::var("HOME").unwrap();
-    if let Some(config_path) =  &args.config {
-        println!("Config path is {:?}", config_path);
+    if let Some(config_path) = config_path {
+        // stderr so query output on stdout stays machine readable
+        eprintln!("Config path is {:?}", config_path);
         // parse config_path to string
         let path: std::path::PathBuf = config_path.parse().expect("User to provide valid path.");
-        let config_string = std::fs::read_to_string(&path).unwrap();
+        let config_string = std::fs::read_to_string(&path).expect("Unable to read config file");
+        // falling back to defaults would silently drop settings like psk and encrypt_db
         config = serde_json::from_str(&config_string)
-            .unwrap_or(
-                Config{
-                    version: "~/.version".to_string(),
-                    listen: "127.0.0.1/tcp/6234".to_string(),
-                    paths: vec!["~/org-sync-test/".to_string()]
-                });
+            .unwrap_or_else(|err| panic!("Config file {:?} is not valid: {}", path, err));
     } else {
         config = Config{
             version: "~/.version".to_string(),
             listen: "127.0.0.1/tcp/6234".to_string(),
             // paths: vec!["~/org/".to_string(), "~/org-roam/".to_string()]
-            paths: vec!["~/org-sync-test/".to_string()]
+            paths: vec!["~/org-sync-test/".to_string()],
+            roots: BTreeMap::new(),
+            ignore: vec![],
+            debounce_ms: 300,
+            control_tcp: None,
+            psk: None,
+            encrypt_db: false,
+            db_key_file: None
         };
     }
-    let mut dirs: Vec<_> = vec![];
-    for path in config.clone().paths.into_iter() {
-        let p = PathBuf::from(path.replace("~", &home).as_str());
-        if p.is_dir() {dirs.push(p)}
-    }
-    let mut paths: Vec<_> = vec![];
-    for path in config.clone().paths.into_iter() {
-        for entry in WalkDir::new(path.replace("~", &home)).into_iter().filter_map(|e| e.ok()) {
-            if !entry.path().is_dir() {
-                paths.push(
-                    FilePath {
-                        home: home.clone(),
-                        full: entry.into_path().into_os_string().into_string().unwrap()
-                    }
-                );
-            }
-            // println!("{}", entry.path().display());
-        }
-    };
-    // println!("Files list: {:?}", &paths.into_iter().map(|x| x.sub_home()).collect::<Vec<String>>());
-    (config, dirs, paths)
+    config
 }
 
 
@@ -58,7 +66,7 @@ pub fn onload(args: Args) -> (Config, Vec<PathBuf>, Vec<FilePath>) {
 pub struct Args {
     /// Config Location
     #[arg(short, long)]
-    config: Option<String>,
+    pub config: Option<String>,
 
     /// Force the state of orgfiles on this machine to be the current state.
     /// Will rewrite local db and then push to DHT
@@ -69,17 +77,100 @@ pub struct Args {
 #[derive(Debug, Parser)]
 pub enum CliArgs {
     Query {
+        /// Print the daemon's reply as JSON
+        #[clap(long)]
+        json: bool,
         #[clap(subcommand)]
-        push: PushPath,
+        command: QueryCommand,
     },
     Serve {
     },
 }
 
 #[derive(Debug, Parser)]
-pub enum PushPath {
+pub enum QueryCommand {
+    /// Show the daemon's peer id, state and counts
+    Status {
+    },
+    /// List peers found on the network
+    Peers {
+    },
+    /// List files tracked in the local db
+    Files {
+    },
+    /// Publish local versions to peers, every file unless a path is given
     Push {
         #[clap(long) ]
         path: Option<String>,
-    }
+    },
+    /// Check peers for newer versions, every file unless a path is given
+    Pull {
+        #[clap(long) ]
+        path: Option<String>,
+    },
+    /// Stop syncing until resumed
+    Pause {
+    },
+    /// Pick up changes made while paused and start syncing again
+    Resume {
+    },
+    /// Show a one-time pairing code, or pair with the device showing --code
+    Pair {
+        #[clap(long) ]
+        code: Option<String>,
+    },
+    /// Stop trusting a paired device, paired devices that hear of it stop trusting it too
+    Revoke {
+        #[clap(long) ]
+        peer: String,
+    },
+    /// Let a revoked device pair again
+    Unrevoke {
+        #[clap(long) ]
+        peer: String,
+    },
+    /// Unlock an encrypted database, prompting for the passphrase unless --key-file is given,
+    /// --new sets the passphrase or key file the first time
+    Unlock {
+        #[clap(long) ]
+        key_file: Option<String>,
+        #[clap(long) ]
+        new: bool,
+    },
+    /// Protect the database key with a new passphrase or key file
+    Rekey {
+        #[clap(long) ]
+        key_file: Option<String>,
+        #[clap(long) ]
+        new_key_file: Option<String>,
+    },
+    /// List files where both local and remote versions were kept
+    Conflicts {
+    },
+    /// Resolve a conflict by keeping the local version or the remote copy
+    Resolve {
+        #[clap(long) ]
+        path: String,
+        #[clap(long, value_parser = ["local", "remote"]) ]
+        keep: String,
+    },
+    /// List stored versions of a file
+    History {
+        #[clap(long) ]
+        path: String,
+    },
+    /// Print the content of a stored version
+    Show {
+        #[clap(long) ]
+        path: String,
+        #[clap(long) ]
+        hash: String,
+    },
+    /// Restore a stored version as a new local edit
+    Restore {
+        #[clap(long) ]
+        path: String,
+        #[clap(long) ]
+        hash: String,
+    },
 }
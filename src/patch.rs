@@ -0,0 +1,90 @@
+// Compact line patches gossiped with Messages::Changed
+use serde::{Deserialize, Serialize};
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+// replace del lines of the base starting at line at with ins
+#[derive(Serialize, Deserialize, Debug)]
+struct Edit {
+    at: usize,
+    del: usize,
+    ins: Vec<String>,
+    // the lines replaced, so a patch for another base is refused
+    // patches from before this was added carry none and aren't checked
+    #[serde(default)]
+    old: Vec<String>,
+}
+
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// Patch turning base into new, as a JSON list of line edits
+pub fn create(base: &str, new: &str) -> String {
+    let old_lines = split_lines(base);
+    let new_lines = split_lines(new);
+    let edits: Vec<Edit> = capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines)
+        .into_iter()
+        .map(|op| op.as_tag_tuple())
+        .filter(|(tag, _, _)| *tag != DiffTag::Equal)
+        .map(|(_, old, new)| Edit {
+            at: old.start,
+            del: old.len(),
+            ins: new_lines[new].iter().map(|line| line.to_string()).collect(),
+            old: old_lines[old].iter().map(|line| line.to_string()).collect(),
+        })
+        .collect();
+    serde_json::to_string(&edits).unwrap()
+}
+
+/// Apply a patch from create to base, None if it doesn't fit
+pub fn apply(base: &str, patch: &str) -> Option<String> {
+    let edits: Vec<Edit> = serde_json::from_str(patch).ok()?;
+    let lines = split_lines(base);
+    let mut out = String::new();
+    let mut pos = 0;
+    for edit in edits {
+        if edit.at < pos || edit.at + edit.del > lines.len() {
+            return None
+        }
+        if !edit.old.is_empty() && lines[edit.at..edit.at + edit.del] != edit.old[..] {
+            return None
+        }
+        out.extend(lines[pos..edit.at].iter().copied());
+        out.extend(edit.ins);
+        pos = edit.at + edit.del;
+    }
+    out.extend(lines[pos..].iter().copied());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let base = "* one\n* two\n* three\n";
+        for new in ["* one\n* two done\n* three\n", "* zero\n* one\n* three\n* four", "", base] {
+            assert_eq!(apply(base, &create(base, new)).as_deref(), Some(new));
+        }
+        assert_eq!(apply("", &create("", "* one\n")).as_deref(), Some("* one\n"));
+    }
+
+    #[test]
+    fn patch_for_another_base_is_refused() {
+        let patch = create("* one\n* two\n* three\n", "* one\n* two done\n* three\n");
+        assert_eq!(apply("* one\n* other\n* three\n", &patch), None);
+        assert_eq!(apply("* one\n", &patch), None);
+    }
+
+    #[test]
+    fn patches_without_replaced_lines_still_apply() {
+        let patch = r#"[{"at":1,"del":1,"ins":["* two done\n"]}]"#;
+        assert_eq!(apply("* one\n* two\n", patch).as_deref(), Some("* one\n* two done\n"));
+    }
+
+    #[test]
+    fn malformed_patch_is_refused() {
+        assert_eq!(apply("* one\n", "not a patch"), None);
+    }
+}
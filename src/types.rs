@@ -1,50 +1,124 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use bendy;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
 
-// version struct
-#[derive(Serialize, Deserialize, Debug)]
+// version struct, one per stored version of a file in the history tree
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Version {
-    pub timestamp: String,
-    pub uid: String,
-    pub update: bool // if update is true then current machine needs to check and update their files
+    pub path: String,
+    pub hash: String,
+    pub previous: Option<String>,
+    pub timestamp: i64,
+    pub device: String // peer the version came from
 }
 
-// TODO method to compare versions and return the most recent with update set to True
-// TODO method to set boolean to False
-
 // for convenience struct for file and path manipulation
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// the key shared with peers is the root name and the path below the root,
+// so each device can keep a root in its own local directory
+#[derive(Clone, Debug)]
 pub struct FilePath {
-    pub home: String,
+    pub root: String,
+    pub base: String,
     pub full: String
 }
 
 impl FilePath {
-    pub fn sub_home(&self) -> String {
-        self.full.replace(&self.home, "")
+    /// logical key as a string, root:/relative/path
+    pub fn to_key(&self) -> String {
+        format!("{}:{}", self.root, self.full.replacen(&self.base, "", 1))
     }
     pub fn to_path(&self) -> &Path {
         Path::new(&self.full)
     }
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.full.replace(&self.home, "")
-                 .as_bytes().to_vec()
-    }
-    pub fn struct_to_bytes(&self) -> Vec<u8> {
-        bendy::serde::to_bytes(&self).unwrap()
+        self.to_key().as_bytes().to_vec()
     }
-    pub fn struct_from_bytes(bytes: Vec<u8>) -> Option<Self> {
-        bendy::serde::from_bytes::<FilePath>(&bytes).ok()
+    /// sibling path for the remote version of a conflicted file
+    pub fn conflict_copy(&self, device: &str) -> Self {
+        let path = self.to_path();
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let ext = match path.extension() {
+            Some(ext) => format!(".{}", ext.to_string_lossy()),
+            None => "".to_string()
+        };
+        let time = chrono::Utc::now().format("%Y%m%d-%H%M%S");
+        let name = format!("{}.conflict-{}-{}{}", stem, device, time, ext);
+        Self {
+            root: self.root.clone(),
+            base: self.base.clone(),
+            full: path.with_file_name(name).into_os_string().into_string().unwrap()
+        }
     }
-    pub fn new_from_key(key: Vec<u8>) -> Self {
+}
+
+/// Logical root names and the local directory each is synced to
+#[derive(Clone, Debug, Default)]
+pub struct Roots {
+    pub roots: BTreeMap<String, PathBuf>
+}
+
+impl Roots {
+    // unnamed paths are named after their directory, two roots can't share a name
+    pub fn from_config(config: &Config) -> Result<Self, String> {
         let home = std::env::var("HOME").unwrap();
-        let mut tilde = "~".to_string();
-        tilde.push_str(&String::from_utf8(key).unwrap());
-        Self {
-            home: home.clone(),
-            full: tilde.replace("~", &home)
+        let mut roots = BTreeMap::new();
+        let named = config.paths.iter()
+            .map(|path| {
+                let dir = PathBuf::from(path.replace("~", &home));
+                match dir.file_name() {
+                    Some(name) => Ok((name.to_string_lossy().to_string(), dir)),
+                    None => Err(format!("path {:?} has no directory name, give it one under \"roots\"", path))
+                }
+            })
+            .chain(config.roots.iter()
+                .map(|(name, path)| Ok((name.clone(), PathBuf::from(path.replace("~", &home))))));
+        for root in named {
+            let (name, dir) = root?;
+            if name.is_empty() || name.contains(':') {
+                return Err(format!("root name {:?} for {:?} can't be empty or contain ':'", name, dir))
+            }
+            if let Some(other) = roots.insert(name.clone(), dir.clone()) {
+                return Err(format!("{:?} and {:?} are both named {:?}, rename one under \"roots\"", other, dir, name))
+            }
+        }
+        roots.retain(|name, dir| {
+            if !dir.is_dir() {
+                println!("Root {:?} at {:?} is not a directory, not syncing it", name, dir);
+            }
+            dir.is_dir()
+        });
+        Ok(Self { roots })
+    }
+    pub fn dirs(&self) -> Vec<PathBuf> {
+        self.roots.values().cloned().collect()
+    }
+    /// the file at path, None when it is under no root
+    pub fn from_path(&self, path: PathBuf) -> Option<FilePath> {
+        let full = path.into_os_string().into_string().ok()?;
+        // the deepest root wins when roots are nested
+        let (name, dir) = self.roots.iter()
+            .filter(|(_, dir)| Path::new(&full).starts_with(dir))
+            .max_by_key(|(_, dir)| dir.components().count())?;
+        Some(FilePath {
+            root: name.clone(),
+            base: dir.to_string_lossy().trim_end_matches('/').to_string(),
+            full
+        })
+    }
+    /// the local file for a key, None when this device doesn't sync its root
+    pub fn from_key(&self, key: &[u8]) -> Option<FilePath> {
+        let key = std::str::from_utf8(key).ok()?;
+        let (name, rel) = key.split_once(':')?;
+        // keys never climb out of their root
+        if !rel.starts_with('/') || Path::new(rel).components().any(|c| c == Component::ParentDir) {
+            return None
         }
+        let base = self.roots.get(name)?.to_string_lossy().trim_end_matches('/').to_string();
+        Some(FilePath {
+            root: name.to_string(),
+            full: format!("{}{}", base, rel),
+            base
+        })
     }
 }
 
@@ -52,5 +126,60 @@ impl FilePath {
 pub struct Config {
     pub version: String,
     pub listen: String,
-    pub paths: Vec<String>
+    pub paths: Vec<String>,
+    // named roots, root name to local directory
+    #[serde(default)]
+    pub roots: BTreeMap<String, String>,
+    // gitignore-style patterns applied under every path
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    // quiet time in milliseconds before a burst of file events is acted on
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    // also serve control requests on this TCP address, any local user can reach it
+    #[serde(default)]
+    pub control_tcp: Option<String>,
+    // pre-shared network key, 64 hex characters or the path to a swarm.key file,
+    // only devices holding the same key can connect when set
+    #[serde(default)]
+    pub psk: Option<String>,
+    // seal values in the local database, the daemon waits for `query unlock` on start
+    #[serde(default)]
+    pub encrypt_db: bool,
+    // unlock with this key file on start instead of waiting for `query unlock`
+    #[serde(default)]
+    pub db_key_file: Option<String>
+}
+
+fn default_debounce_ms() -> u64 {
+    300
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> Config {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn roots_sharing_a_name_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("one/notes");
+        let second = dir.path().join("two/notes");
+        let json = format!(r#"{{"version": "v", "listen": "l", "paths": [{:?}, {:?}]}}"#, first, second);
+        assert!(Roots::from_config(&config(&json)).is_err());
+        // naming one of them explicitly settles it
+        let json = format!(r#"{{"version": "v", "listen": "l", "paths": [{:?}], "roots": {{"work": {:?}}}}}"#, first, second);
+        assert!(Roots::from_config(&config(&json)).is_ok());
+    }
+
+    #[test]
+    fn root_without_a_directory_name_is_refused() {
+        let json = r#"{"version": "v", "listen": "l", "paths": ["/"]}"#;
+        assert!(Roots::from_config(&config(json)).is_err());
+        let json = r#"{"version": "v", "listen": "l", "paths": [], "roots": {"all": "/"}}"#;
+        assert_eq!(Roots::from_config(&config(json)).unwrap().dirs(), vec![PathBuf::from("/")]);
+    }
 }
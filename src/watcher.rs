@@ -5,25 +5,64 @@ use futures::{
 use futures::channel::{mpsc};
 use futures::{prelude::*, select};
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher, Config};
-use notify::event::{CreateKind, EventKind, ModifyKind, DataChange, RemoveKind};
+use notify::event::{CreateKind, EventKind, ModifyKind, RemoveKind, RenameMode};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use crate::netcommand::*;
+use crate::ignores::{IgnoreRules, SharedRules};
+use crate::netevent::SharedTransfers;
+
+// file events buffered between notify and the watcher task
+const EVENT_QUEUE: usize = 1024;
+// how often settled paths are checked for when no events arrive
+const TICK: Duration = Duration::from_millis(50);
+
+// what a path's burst of events amounts to once it settles
+#[derive(Debug, Clone, PartialEq)]
+enum Pending {
+    Add,
+    Change,
+    Delete,
+    Move { from: PathBuf },
+}
 
 #[derive(Clone)]
 pub struct WatcherSender {
-    pub sender: mpsc::Sender<Command>
+    pub sender: mpsc::Sender<Command>,
+    pub rules: SharedRules,
+    pub transfers: SharedTransfers,
+    // how long a path has to be quiet before its events are sent on
+    pub quiet: Duration,
+    pending: HashMap<PathBuf, (Pending, Instant)>,
 }
 
 
 
 impl WatcherSender {
+    pub fn new(sender: mpsc::Sender<Command>, rules: SharedRules, transfers: SharedTransfers, quiet: Duration) -> Self {
+        Self {
+            sender,
+            rules,
+            transfers,
+            quiet,
+            pending: HashMap::new()
+        }
+    }
+
     pub async fn watch(mut self, paths: Vec<PathBuf>) -> notify::Result<()> {
-        let (tx, rx) = std::sync::mpsc::channel();
+        // notify calls back on its own thread, blocking there when the queue is full
+        // holds events in the kernel rather than on an executor thread
+        let (mut tx, mut rx) = channel(EVENT_QUEUE);
 
         // Automatically select the best implementation for your platform.
         // You can also access each implementation directly e.g. INotifyWatcher.
-        let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
+        let mut watcher = RecommendedWatcher::new(
+            move |res| {
+                _ = futures::executor::block_on(tx.send(res));
+            },
+            Config::default())?;
 
         // Add a path to be watched. All files and directories at that path and
         // below will be monitored for changes.
@@ -31,36 +70,260 @@ impl WatcherSender {
             watcher.watch(path.as_path().as_ref(), RecursiveMode::Recursive)?;
         }
 
+        // the source of a rename, held until the matching destination shows up
+        let mut moved_from: Option<(Option<usize>, PathBuf)> = None;
         loop {
-            for res in &rx {
-                match res {
-                    Ok(Event{kind: EventKind::Create(CreateKind::File), paths: pathlist, ..}) => {
-                        println!("added: {:?}", pathlist);
-                        self.create_file_hook(pathlist[0].clone());
-                    },
-                    Ok(Event{kind: EventKind::Remove(RemoveKind::File), paths: pathlist, ..}) => println!("removed: {:?}", pathlist),
-                    Ok(Event{ kind: EventKind::Modify(ModifyKind::Data(DataChange::Content)), paths: pathlist, ..}) => println!("changed: {:?}", pathlist),
-                    Ok(event) => {},
-                    Err(e) => println!("watch error: {:?}", e),
+            let res = match async_std::future::timeout(TICK, rx.next()).await {
+                Ok(Some(res)) => res,
+                Err(_) => {
+                    // moved out of the watched dirs
+                    if let Some((_, path)) = moved_from.take() {
+                        self.queue(path, Pending::Delete);
+                    }
+                    self.flush().await;
+                    continue
+                },
+                Ok(None) => return Ok(())
+            };
+            if let Ok(event) = &res {
+                if event.paths.iter().any(|path| IgnoreRules::is_ignore_file(path)) {
+                    self.rules.write().unwrap().reload();
                 }
+                // a rename between an ignored and a synced path is sorted out by the network loop
+                if event.paths.iter().all(|path| self.rules.read().unwrap().is_ignored(path)) {
+                    continue
+                }
+            }
+            match res {
+                Ok(Event{kind: EventKind::Modify(ModifyKind::Name(RenameMode::From)), paths: pathlist, attrs}) => {
+                    if let Some((_, path)) = moved_from.take() {
+                        self.queue(path, Pending::Delete);
+                    }
+                    moved_from = Some((attrs.tracker(), pathlist[0].clone()));
+                },
+                Ok(Event{kind: EventKind::Modify(ModifyKind::Name(RenameMode::To)), paths: pathlist, attrs}) => {
+                    // paired with the held source, a Both event follows
+                    if !matches!(&moved_from, Some((tracker, _)) if tracker.is_some() && *tracker == attrs.tracker()) {
+                        println!("moved in: {:?}", pathlist);
+                        self.queue(pathlist[0].clone(), Pending::Add);
+                    }
+                },
+                Ok(Event{kind: EventKind::Modify(ModifyKind::Name(RenameMode::Both)), paths: pathlist, ..}) => {
+                    moved_from = None;
+                    println!("moved: {:?}", pathlist);
+                    self.queue_move(pathlist[0].clone(), pathlist[1].clone());
+                },
+                res => {
+                    if let Some((_, path)) = moved_from.take() {
+                        self.queue(path, Pending::Delete);
+                    }
+                    match res {
+                        Ok(Event{kind: EventKind::Create(CreateKind::File), paths: pathlist, ..}) => {
+                            println!("added: {:?}", pathlist);
+                            self.queue(pathlist[0].clone(), Pending::Add);
+                        },
+                        Ok(Event{kind: EventKind::Remove(RemoveKind::File), paths: pathlist, ..}) => {
+                            println!("removed: {:?}", pathlist);
+                            self.queue(pathlist[0].clone(), Pending::Delete);
+                        },
+                        Ok(Event{ kind: EventKind::Modify(ModifyKind::Data(_)), paths: pathlist, ..}) => {
+                            println!("changed: {:?}", pathlist);
+                            self.queue(pathlist[0].clone(), Pending::Change);
+                        },
+                        Ok(event) => {},
+                        Err(e) => println!("watch error: {:?}", e),
+                    }
+                }
+            }
+            self.flush().await;
+        }
+    }
+
+    // a file the network loop wrote is not a local edit while it holds what was written,
+    // each expected write is used up by the first check of its path
+    fn own_write(&self, path: &PathBuf) -> bool {
+        let expected = match path.to_str().and_then(|path| self.transfers.lock().unwrap().remove(path)) {
+            Some(expected) if expected.expires > Instant::now() => expected,
+            _ => return false
+        };
+        std::fs::read(path).map_or(false, |data| sha256::digest(&data[..]) == expected.digest)
+    }
+
+    // fold an event into what is pending for the path and restart its quiet window
+    fn queue(&mut self, path: PathBuf, event: Pending) {
+        let merged = match (self.pending.remove(&path).map(|(pending, _)| pending), event) {
+            (None, event) => event,
+            // created and removed before it settled
+            (Some(Pending::Add), Pending::Delete) => return,
+            (Some(Pending::Add), Pending::Change) => Pending::Add,
+            // replaced by an editor saving through a new file
+            (Some(Pending::Delete), Pending::Add) => Pending::Change,
+            (Some(Pending::Move { from }), Pending::Change) => Pending::Move { from },
+            // moved then removed, only the source ever existed for peers
+            (Some(Pending::Move { from }), Pending::Delete) => {
+                self.pending.insert(from, (Pending::Delete, Instant::now()));
+                return
+            },
+            (_, event) => event
+        };
+        self.pending.insert(path, (merged, Instant::now()));
+    }
+
+    fn queue_move(&mut self, from: PathBuf, to: PathBuf) {
+        let moved = match self.pending.remove(&from).map(|(pending, _)| pending) {
+            // a temp file written and renamed into place before it settled
+            Some(Pending::Add) => Pending::Add,
+            // renamed again, peers only know the first source
+            Some(Pending::Move { from }) => Pending::Move { from },
+            _ => Pending::Move { from }
+        };
+        self.queue(to, moved);
+    }
+
+    // send on paths that have been quiet for the whole window, oldest first
+    async fn flush(&mut self) {
+        let now = Instant::now();
+        let mut settled: Vec<(PathBuf, Pending, Instant)> = self.pending.iter()
+            .filter(|(_, (_, seen))| now.duration_since(*seen) >= self.quiet)
+            .map(|(path, (pending, seen))| (path.clone(), pending.clone(), *seen))
+            .collect();
+        settled.sort_by_key(|(_, _, seen)| *seen);
+        for (path, pending, _) in settled {
+            self.pending.remove(&path);
+            if matches!(pending, Pending::Add | Pending::Change) && self.own_write(&path) {
+                continue
+            }
+            match pending {
+                Pending::Add => self.create_file_hook(path).await,
+                Pending::Change => self.change_file_hook(path).await,
+                Pending::Delete => self.delete_file_hook(path).await,
+                Pending::Move { from } => self.move_file_hook(from, path).await,
             }
         }
     }
 
-    // TODO add hook for updating local state/DHT and sending message for:
-    // 1. change of file
-    // 2. adding or deleting files
+    // hooks pass file events to the network loop which updates local state/DHT
+    // and sends messages to peers
+
+    async fn create_file_hook(&mut self, path: PathBuf) {
+        self.send(Command::EditFileAdd { path: path }).await
+    }
 
-    fn create_file_hook(&mut self, path: PathBuf) {
-        self.sender.try_send(Command::EditFileAdd { path: path })
+    async fn delete_file_hook(&mut self, path: PathBuf) {
+        self.send(Command::EditFileDelete { path: path }).await
+    }
+
+    async fn change_file_hook(&mut self, path: PathBuf) {
+        self.send(Command::EditFileChange { path: path }).await
+    }
+
+    async fn move_file_hook(&mut self, from: PathBuf, to: PathBuf) {
+        self.send(Command::EditFileMove { from: from, to: to }).await
+    }
+
+    // waits while the network loop is busy, a full channel is not an error
+    async fn send(&mut self, command: Command) {
+        self.sender.send(command).await
         .expect("Command receiver not to be dropped.")
     }
+}
 
-    fn delete_file_hook(path: PathBuf, mut sender: mpsc::Sender<Command>) {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::netevent::ExpectedWrite;
 
+    fn watcher() -> (WatcherSender, mpsc::Receiver<Command>) {
+        let (sender, receiver) = mpsc::channel(8);
+        (WatcherSender::new(sender, IgnoreRules::shared(vec![], vec![]),
+            Arc::new(Mutex::new(HashMap::new())), Duration::ZERO), receiver)
     }
 
-    fn change_file_hook(path: PathBuf, mut sender: mpsc::Sender<Command>) {
+    // what the network loop would be sent
+    fn sent(receiver: &mut mpsc::Receiver<Command>) -> Vec<String> {
+        let mut sent = vec![];
+        while let Ok(Some(command)) = receiver.try_next() {
+            sent.push(format!("{:?}", command));
+        }
+        sent
+    }
+
+    fn expect_write(watcher: &WatcherSender, path: &PathBuf, data: &str) {
+        watcher.transfers.lock().unwrap().insert(path.to_string_lossy().into(), ExpectedWrite {
+            digest: sha256::digest(data.as_bytes()),
+            expires: Instant::now() + Duration::from_secs(60)
+        });
+    }
+
+    fn pending(watcher: &WatcherSender, path: &str) -> Option<Pending> {
+        watcher.pending.get(&PathBuf::from(path)).map(|(pending, _)| pending.clone())
+    }
+
+    #[test]
+    fn events_for_a_path_fold_into_one() {
+        let (mut watcher, _receiver) = watcher();
+        watcher.queue("/r/a.org".into(), Pending::Add);
+        watcher.queue("/r/a.org".into(), Pending::Change);
+        assert_eq!(pending(&watcher, "/r/a.org"), Some(Pending::Add));
+        // created and removed before it settled never reaches peers
+        watcher.queue("/r/a.org".into(), Pending::Delete);
+        assert_eq!(pending(&watcher, "/r/a.org"), None);
+        // an editor replacing the file is an edit
+        watcher.queue("/r/b.org".into(), Pending::Delete);
+        watcher.queue("/r/b.org".into(), Pending::Add);
+        assert_eq!(pending(&watcher, "/r/b.org"), Some(Pending::Change));
+    }
+
+    #[test]
+    fn moves_keep_their_first_source() {
+        let (mut watcher, _receiver) = watcher();
+        watcher.queue_move("/r/a.org".into(), "/r/b.org".into());
+        watcher.queue("/r/b.org".into(), Pending::Change);
+        assert_eq!(pending(&watcher, "/r/b.org"), Some(Pending::Move { from: "/r/a.org".into() }));
+        watcher.queue_move("/r/b.org".into(), "/r/c.org".into());
+        assert_eq!(pending(&watcher, "/r/b.org"), None);
+        assert_eq!(pending(&watcher, "/r/c.org"), Some(Pending::Move { from: "/r/a.org".into() }));
+        // removed after moving, peers only knew the source
+        watcher.queue("/r/c.org".into(), Pending::Delete);
+        assert_eq!(pending(&watcher, "/r/c.org"), None);
+        assert_eq!(pending(&watcher, "/r/a.org"), Some(Pending::Delete));
+    }
+
+    #[test]
+    fn temp_files_renamed_into_place_are_adds() {
+        let (mut watcher, _receiver) = watcher();
+        watcher.queue("/r/.a.org.tmp".into(), Pending::Add);
+        watcher.queue_move("/r/.a.org.tmp".into(), "/r/a.org".into());
+        assert_eq!(pending(&watcher, "/r/.a.org.tmp"), None);
+        assert_eq!(pending(&watcher, "/r/a.org"), Some(Pending::Add));
+    }
+
+    #[test]
+    fn the_echo_of_a_daemon_write_is_not_an_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.org");
+        let (mut watcher, mut receiver) = watcher();
+        std::fs::write(&path, "received").unwrap();
+        expect_write(&watcher, &path, "received");
+        watcher.queue(path.clone(), Pending::Change);
+        async_std::task::block_on(watcher.flush());
+        assert!(sent(&mut receiver).is_empty());
+        // the expected write is used up, a later event for the same content is passed on
+        watcher.queue(path.clone(), Pending::Change);
+        async_std::task::block_on(watcher.flush());
+        assert_eq!(sent(&mut receiver).len(), 1);
+    }
 
+    #[test]
+    fn a_user_edit_after_a_daemon_write_is_passed_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.org");
+        let (mut watcher, mut receiver) = watcher();
+        expect_write(&watcher, &path, "received");
+        std::fs::write(&path, "received and edited").unwrap();
+        watcher.queue(path.clone(), Pending::Change);
+        async_std::task::block_on(watcher.flush());
+        assert_eq!(sent(&mut receiver), vec![format!("{:?}", Command::EditFileChange { path })]);
     }
 }